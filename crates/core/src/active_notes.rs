@@ -0,0 +1,103 @@
+use crate::{
+    AllNotesOffEvent, KeyboardRegister, StartProgramEvent,
+    input::make_key_event,
+    pedal::Pedals,
+    pipeline::{KeyPipeline, KeyPipelineSet},
+};
+use bevy::{platform::collections::HashSet, prelude::*, window::WindowFocused};
+use bevy_midi_graph::midi::event::Event;
+
+pub struct ActiveNotesPlugin;
+
+impl Plugin for ActiveNotesPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ActiveNotes>()
+            .add_systems(
+                PreUpdate,
+                request_all_notes_off.in_set(KeyPipelineSet::Collect),
+            )
+            .add_systems(
+                PreUpdate,
+                (release_all_notes, track_active_notes)
+                    .chain()
+                    .in_set(KeyPipelineSet::Release),
+            );
+    }
+}
+
+/// Notes currently sounding on each register, as sent to the output.
+#[derive(Resource, Default)]
+pub struct ActiveNotes {
+    lower: HashSet<u8>,
+    upper: HashSet<u8>,
+}
+
+impl ActiveNotes {
+    pub fn notes(&self, register: KeyboardRegister) -> &HashSet<u8> {
+        match register {
+            KeyboardRegister::Lower => &self.lower,
+            KeyboardRegister::Upper => &self.upper,
+        }
+    }
+
+    fn notes_mut(&mut self, register: KeyboardRegister) -> &mut HashSet<u8> {
+        match register {
+            KeyboardRegister::Lower => &mut self.lower,
+            KeyboardRegister::Upper => &mut self.upper,
+        }
+    }
+}
+
+/// Return the keyboard to rest when the window loses focus or a program is
+/// requested. The notes sounding on the old program are already released
+/// by the output, in the frame the program was requested.
+fn request_all_notes_off(
+    mut focus_events: EventReader<WindowFocused>,
+    mut program_events: EventReader<StartProgramEvent>,
+    mut all_notes_off: EventWriter<AllNotesOffEvent>,
+) {
+    let lost_focus = focus_events.read().any(|event| !event.focused);
+    let changed_program = program_events.read().count() > 0;
+    if lost_focus || changed_program {
+        all_notes_off.write(AllNotesOffEvent);
+    }
+}
+
+fn release_all_notes(
+    mut events: EventReader<AllNotesOffEvent>,
+    active_notes: Res<ActiveNotes>,
+    mut pedals: ResMut<Pedals>,
+    mut pipeline: ResMut<KeyPipeline>,
+) {
+    if events.is_empty() {
+        return;
+    }
+    events.clear();
+    pedals.release_all();
+    pipeline.events.clear();
+    for register in [KeyboardRegister::Lower, KeyboardRegister::Upper] {
+        for note in active_notes.notes(register).iter() {
+            pipeline.events.push(make_key_event(
+                register,
+                Event::NoteOff {
+                    note: *note,
+                    vel: 1.0,
+                },
+            ));
+        }
+    }
+}
+
+fn track_active_notes(pipeline: Res<KeyPipeline>, mut active_notes: ResMut<ActiveNotes>) {
+    for event in pipeline.events.iter() {
+        match event.message.data {
+            Event::NoteOn { note, .. } => {
+                active_notes.notes_mut(event.register).insert(note);
+            }
+            Event::NoteOff { note, .. } => {
+                active_notes.notes_mut(event.register).remove(&note);
+            }
+            _ => {}
+        }
+    }
+}
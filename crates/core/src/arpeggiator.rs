@@ -0,0 +1,328 @@
+use crate::{
+    AllNotesOffEvent, KeyEvent, KeySource, KeyboardRegister,
+    bus::{Bus, SCHEDULE_AHEAD_SECS},
+    input::make_key_event,
+    metronome::Metronome,
+    pipeline::{KeyPipeline, KeyPipelineSet},
+};
+use bevy::prelude::*;
+use bevy_midi_graph::midi::event::Event;
+
+const LOWER_KEY: KeyCode = KeyCode::ScrollLock;
+const UPPER_KEY: KeyCode = KeyCode::Pause;
+const RATES: [u8; 4] = [1, 2, 3, 4];
+const GATES: [f32; 4] = [0.25, 0.5, 0.75, 1.0];
+const OCTAVES_MAX: u8 = 3;
+
+pub struct ArpeggiatorPlugin;
+
+impl Plugin for ArpeggiatorPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Arpeggiator>().add_systems(
+            PreUpdate,
+            (control_arpeggiator, arpeggiate)
+                .chain()
+                .in_set(KeyPipelineSet::Arpeggiate),
+        );
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ArpMode {
+    #[default]
+    Off,
+    On,
+    /// Notes keep playing after their keys are released, until a new chord
+    /// is started
+    Latched,
+}
+
+impl ArpMode {
+    fn next(self) -> Self {
+        match self {
+            ArpMode::Off => ArpMode::On,
+            ArpMode::On => ArpMode::Latched,
+            ArpMode::Latched => ArpMode::Off,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ArpPattern {
+    #[default]
+    Up,
+    Down,
+    UpDown,
+    Random,
+    AsPlayed,
+}
+
+impl ArpPattern {
+    fn next(self) -> Self {
+        match self {
+            ArpPattern::Up => ArpPattern::Down,
+            ArpPattern::Down => ArpPattern::UpDown,
+            ArpPattern::UpDown => ArpPattern::Random,
+            ArpPattern::Random => ArpPattern::AsPlayed,
+            ArpPattern::AsPlayed => ArpPattern::Up,
+        }
+    }
+}
+
+/// How one register arpeggiates its held notes.
+/// `rate` is the number of notes per metronome beat, `octaves` the number of
+/// octaves the pattern climbs through and `gate` the share of each step a
+/// note sounds for.
+#[derive(Clone, Debug)]
+pub struct ArpSettings {
+    pub mode: ArpMode,
+    pub pattern: ArpPattern,
+    pub rate: u8,
+    pub octaves: u8,
+    pub gate: f32,
+}
+
+impl Default for ArpSettings {
+    fn default() -> Self {
+        Self {
+            mode: ArpMode::Off,
+            pattern: ArpPattern::Up,
+            rate: 4,
+            octaves: 1,
+            gate: 0.5,
+        }
+    }
+}
+
+/// Notes held on a register and the pattern playing through them
+#[derive(Default)]
+struct ArpVoice {
+    pressed: Vec<u8>,
+    notes: Vec<u8>,
+    vel: f32,
+    position: usize,
+    /// When the next step is due on the output bus's clock
+    next_step_secs: Option<f64>,
+    random_state: u32,
+}
+
+impl ArpVoice {
+    fn press(&mut self, note: u8, vel: f32, mode: ArpMode) {
+        if mode == ArpMode::Latched && self.pressed.is_empty() {
+            // A new chord replaces the latched one
+            self.notes.clear();
+        }
+        self.pressed.push(note);
+        if !self.notes.contains(&note) {
+            self.notes.push(note);
+        }
+        self.vel = vel;
+    }
+
+    fn release(&mut self, note: u8, mode: ArpMode) {
+        self.pressed.retain(|pressed| *pressed != note);
+        if mode != ArpMode::Latched {
+            self.notes.retain(|held| *held != note);
+        }
+    }
+
+    /// The held notes in pattern order, through every octave
+    fn sequence(&self, settings: &ArpSettings) -> Vec<u8> {
+        let mut notes = self.notes.clone();
+        if settings.pattern != ArpPattern::AsPlayed {
+            notes.sort_unstable();
+        }
+        let mut sequence: Vec<u8> = (0..settings.octaves.max(1))
+            .flat_map(|octave| notes.iter().map(move |note| note + 12 * octave))
+            .filter(|note| *note < 128)
+            .collect();
+        match settings.pattern {
+            ArpPattern::Down => sequence.reverse(),
+            ArpPattern::UpDown if sequence.len() > 2 => {
+                let descent: Vec<u8> = sequence[1..sequence.len() - 1]
+                    .iter()
+                    .rev()
+                    .copied()
+                    .collect();
+                sequence.extend(descent);
+            }
+            _ => {}
+        }
+        sequence
+    }
+
+    fn next_random(&mut self) -> usize {
+        // Xorshift, plenty for picking notes
+        let mut x = self.random_state.max(1);
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random_state = x;
+        x as usize
+    }
+}
+
+/// A key event to sound at a time on the output bus's clock
+fn scheduled_key_event(register: KeyboardRegister, data: Event, secs: f64) -> KeyEvent {
+    KeyEvent {
+        source: KeySource::Arp,
+        due_secs: Some(secs),
+        ..make_key_event(register, data)
+    }
+}
+
+/// Arpeggiator for each register.
+/// ScrollLock and Pause cycle the lower and upper register through off, on
+/// and latched. Held with Shift they cycle the pattern, with Ctrl the rate,
+/// with Shift and Ctrl together the gate and with Alt the octave range.
+/// Steps follow the metronome tempo, and each step's notes are scheduled a
+/// little ahead so the output bus plays them at their sample.
+#[derive(Resource, Default)]
+pub struct Arpeggiator {
+    pub lower: ArpSettings,
+    pub upper: ArpSettings,
+    lower_voice: ArpVoice,
+    upper_voice: ArpVoice,
+}
+
+impl Arpeggiator {
+    pub fn settings(&self, register: KeyboardRegister) -> &ArpSettings {
+        match register {
+            KeyboardRegister::Lower => &self.lower,
+            KeyboardRegister::Upper => &self.upper,
+        }
+    }
+
+    fn register_mut(&mut self, register: KeyboardRegister) -> (&mut ArpSettings, &mut ArpVoice) {
+        match register {
+            KeyboardRegister::Lower => (&mut self.lower, &mut self.lower_voice),
+            KeyboardRegister::Upper => (&mut self.upper, &mut self.upper_voice),
+        }
+    }
+}
+
+fn control_arpeggiator(inputs: Res<ButtonInput<KeyCode>>, mut arpeggiator: ResMut<Arpeggiator>) {
+    for (key, register) in [
+        (LOWER_KEY, KeyboardRegister::Lower),
+        (UPPER_KEY, KeyboardRegister::Upper),
+    ] {
+        if !inputs.just_pressed(key) {
+            continue;
+        }
+        let (settings, voice) = arpeggiator.register_mut(register);
+        let shift = inputs.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+        let ctrl = inputs.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+        if shift && ctrl {
+            let index = GATES
+                .iter()
+                .position(|gate| *gate == settings.gate)
+                .map_or(0, |index| (index + 1) % GATES.len());
+            settings.gate = GATES[index];
+        } else if shift {
+            settings.pattern = settings.pattern.next();
+        } else if ctrl {
+            let index = RATES
+                .iter()
+                .position(|rate| *rate == settings.rate)
+                .map_or(0, |index| (index + 1) % RATES.len());
+            settings.rate = RATES[index];
+        } else if inputs.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]) {
+            settings.octaves = settings.octaves % OCTAVES_MAX + 1;
+        } else {
+            let mode = settings.mode.next();
+            // Notes already scheduled are released by their own note offs
+            match mode {
+                ArpMode::Off => *voice = ArpVoice::default(),
+                _ => voice.notes = voice.pressed.clone(),
+            }
+            settings.mode = mode;
+            println!("DID SET ARPEGGIATOR: {:?} {:?}", register, mode);
+        }
+    }
+}
+
+/// Take the notes of arpeggiating registers out of the pipeline and play
+/// their pattern instead, scheduling the steps due before the bus plays the
+/// next frame or so
+fn arpeggiate(
+    mut all_notes_off: EventReader<AllNotesOffEvent>,
+    metronome: Res<Metronome>,
+    bus: Res<Bus>,
+    mut arpeggiator: ResMut<Arpeggiator>,
+    mut pipeline: ResMut<KeyPipeline>,
+) {
+    let notes_off = !all_notes_off.is_empty();
+    all_notes_off.clear();
+    if arpeggiator.lower.mode == ArpMode::Off && arpeggiator.upper.mode == ArpMode::Off {
+        return;
+    }
+    let now_secs = bus.audio_secs();
+    let beat_secs = metronome.beat_secs();
+    // Step state changes every frame but is not shown
+    let arpeggiator = arpeggiator.bypass_change_detection();
+    let mut events = Vec::with_capacity(pipeline.events.len());
+    for event in pipeline.events.drain(..) {
+        let (settings, voice) = arpeggiator.register_mut(event.register);
+        match (settings.mode, event.message.data.clone()) {
+            (ArpMode::Off, _) => events.push(event),
+            (mode, Event::NoteOn { note, vel }) => voice.press(note, vel, mode),
+            // Keys pressed before the arpeggiator was on are released as usual
+            (mode, Event::NoteOff { note, .. }) if voice.pressed.contains(&note) => {
+                voice.release(note, mode)
+            }
+            _ => events.push(event),
+        }
+    }
+
+    for register in [KeyboardRegister::Lower, KeyboardRegister::Upper] {
+        let (settings, voice) = arpeggiator.register_mut(register);
+        if settings.mode == ArpMode::Off {
+            continue;
+        }
+        if notes_off {
+            *voice = ArpVoice::default();
+            continue;
+        }
+        if voice.notes.is_empty() {
+            voice.position = 0;
+            voice.next_step_secs = None;
+            continue;
+        }
+        let step_secs = beat_secs / settings.rate.max(1) as f64;
+        let next_step = |secs: f64| ((secs / step_secs).floor() + 1.0) * step_secs;
+        // The first note plays at once and the rest on the tempo's steps,
+        // skipping any that were missed while frames were held up
+        let mut step_at = match voice.next_step_secs {
+            Some(secs) if secs >= now_secs => secs,
+            Some(_) => next_step(now_secs),
+            None => now_secs,
+        };
+        let gate = settings.gate.clamp(0.05, 1.0) as f64;
+        while step_at < now_secs + SCHEDULE_AHEAD_SECS {
+            let sequence = voice.sequence(settings);
+            let index = match settings.pattern {
+                ArpPattern::Random => voice.next_random() % sequence.len(),
+                _ => voice.position % sequence.len(),
+            };
+            voice.position += 1;
+            let note = sequence[index];
+            let next_step_at = next_step(step_at);
+            events.push(scheduled_key_event(
+                register,
+                Event::NoteOn {
+                    note,
+                    vel: voice.vel,
+                },
+                step_at,
+            ));
+            events.push(scheduled_key_event(
+                register,
+                Event::NoteOff { note, vel: 1.0 },
+                step_at + gate * (next_step_at - step_at),
+            ));
+            step_at = next_step_at;
+        }
+        voice.next_step_secs = Some(step_at);
+    }
+    pipeline.events = events;
+}
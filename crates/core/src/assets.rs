@@ -1,84 +1,286 @@
-use crate::StartProgramEvent;
-use bevy::{asset::LoadState, prelude::*};
-use bevy_midi_graph::{
-    GraphAssetLoader, MidiFileSource, MidiGraph, MidiGraphAudioContext, Sf2FileSource,
-    WaveFileSource,
+use crate::{
+    ActiveProgram, ProgramLoadFailedEvent, ProgramTarget, StartProgramEvent,
+    effects::EffectsSettings, ensemble::check_program, utils::JsonAssetLoader,
 };
+use bevy::{
+    asset::{AssetLoadError, LoadState, RecursiveDependencyLoadState},
+    prelude::*,
+    reflect::TypePath,
+};
+use bevy_midi_graph::{MidiFileSource, MidiGraph, Sf2FileSource, WaveFileSource};
+use serde::Deserialize;
+use std::{fmt, sync::Arc};
 
 const DEFAULT_PROGRAM: usize = 1;
+const PROGRAM_MANIFEST: &str = "programs.manifest.json";
 
 pub struct AssetsPlugin;
 
 impl Plugin for AssetsPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<ProgramAssets>()
-            .add_systems(Startup, init_program_assets)
-            .add_systems(Update, check_graph_assets_ready);
+        app.init_asset::<ProgramManifest>()
+            .register_asset_loader(JsonAssetLoader::<ProgramManifest>::new(&["manifest.json"]))
+            .init_resource::<ProgramAssets>()
+            .add_systems(Startup, init_program_manifest)
+            .add_systems(
+                Update,
+                (
+                    init_program_assets,
+                    reload_modified_programs,
+                    check_graph_assets_ready,
+                )
+                    .chain(),
+            );
+    }
+}
+
+/// The list of programs available to play, in program number order
+#[derive(Asset, TypePath, Deserialize)]
+pub struct ProgramManifest {
+    pub programs: Vec<ProgramEntry>,
+}
+
+#[derive(Deserialize)]
+pub struct ProgramEntry {
+    pub path: String,
+    pub name: String,
+    #[serde(default)]
+    pub category: String,
+    #[serde(default)]
+    pub key: Option<KeyCode>,
+    /// The effects the program plays through, dry if not given
+    #[serde(default)]
+    pub effects: EffectsSettings,
+}
+
+pub struct ProgramAsset {
+    pub program_no: usize,
+    pub name: String,
+    pub category: String,
+    pub key: Option<KeyCode>,
+    pub effects: EffectsSettings,
+    pub load_state: LoadState,
+    pub error: Option<ProgramLoadError>,
+    pub handle: Handle<MidiGraph>,
+}
+
+impl ProgramAsset {
+    /// Whether the program has loaded and can be switched to
+    pub fn is_ready(&self) -> bool {
+        self.load_state.is_loaded() && self.error.is_none()
+    }
+}
+
+/// Why a program could not be made ready to play
+#[derive(Clone, Debug)]
+pub enum ProgramLoadError {
+    Asset(Arc<AssetLoadError>),
+    Graph(String),
+}
+
+impl fmt::Display for ProgramLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgramLoadError::Asset(error) => write!(f, "{}", error),
+            ProgramLoadError::Graph(error) => write!(f, "{}", error),
+        }
     }
 }
 
 #[derive(Resource, Default)]
 pub struct ProgramAssets {
-    pub programs: Vec<(LoadState, usize, Handle<MidiGraph>)>,
+    pub manifest: Handle<ProgramManifest>,
+    pub programs: Vec<ProgramAsset>,
+    /// Counts the times a program has finished loading
+    loads: u64,
+}
+
+impl ProgramAssets {
+    /// Changes whenever a program finishes loading, so that whatever plays
+    /// it knows to play the reloaded program instead
+    pub(crate) fn loads(&self) -> u64 {
+        self.loads
+    }
+
+    pub fn get(&self, program_no: usize) -> Option<&ProgramAsset> {
+        self.programs
+            .iter()
+            .find(|program| program.program_no == program_no)
+    }
+
+    pub fn program_for_key(&self, key: &KeyCode) -> Option<usize> {
+        self.programs
+            .iter()
+            .find(|program| program.key.as_ref() == Some(key))
+            .map(|program| program.program_no)
+    }
+
+    /// The program `step` places away from the given one, wrapping around
+    pub fn program_after(&self, program_no: Option<usize>, step: isize) -> Option<usize> {
+        if self.programs.is_empty() {
+            return None;
+        }
+        let count = self.programs.len() as isize;
+        let index = program_no
+            .and_then(|program_no| {
+                self.programs
+                    .iter()
+                    .position(|program| program.program_no == program_no)
+            })
+            .map_or(0, |index| (index as isize + step).rem_euclid(count));
+        Some(self.programs[index as usize].program_no)
+    }
+}
+
+fn init_program_manifest(server: Res<AssetServer>, mut program_data: ResMut<ProgramAssets>) {
+    program_data.manifest = server.load(PROGRAM_MANIFEST);
 }
 
-fn init_program_assets(server: Res<AssetServer>, mut program_data: ResMut<ProgramAssets>) {
-    let asset_names = [
-        "f1.json", "f2.json", "f3.json", "f4.json", "f5.json", "f6.json", "f7.json", "f8.json",
-        "f9.json", "f10.json", "f11.json", "f12.json",
-    ];
-    for (index, name) in asset_names.iter().enumerate() {
-        program_data
+fn init_program_assets(
+    mut events: EventReader<AssetEvent<ProgramManifest>>,
+    server: Res<AssetServer>,
+    manifests: Res<Assets<ProgramManifest>>,
+    active_program: Res<ActiveProgram>,
+    mut program_data: ResMut<ProgramAssets>,
+    mut program_events: EventWriter<StartProgramEvent>,
+) {
+    for event in events.read() {
+        if !event.is_loaded_with_dependencies(&program_data.manifest)
+            && !event.is_modified(&program_data.manifest)
+        {
+            continue;
+        }
+        let Some(manifest) = manifests.get(&program_data.manifest) else {
+            continue;
+        };
+        program_data.programs = manifest
             .programs
-            .push((LoadState::NotLoaded, index + 1, server.load(*name)));
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| ProgramAsset {
+                program_no: index + 1,
+                name: entry.name.clone(),
+                category: entry.category.clone(),
+                key: entry.key,
+                effects: entry.effects.clone(),
+                load_state: LoadState::NotLoaded,
+                error: None,
+                handle: server.load(&entry.path),
+            })
+            .collect();
+        let program_no = active_program.program_no.unwrap_or(DEFAULT_PROGRAM);
+        program_events.write(StartProgramEvent {
+            program_no,
+            target: ProgramTarget::Keyboard,
+        });
     }
 }
 
+/// Mark programs for loading again when their graph or any source asset
+/// changes on disk, or when a program that failed has been fixed
+fn reload_modified_programs(
+    mut graph_events: EventReader<AssetEvent<MidiGraph>>,
+    mut midi_events: EventReader<AssetEvent<MidiFileSource>>,
+    mut sf2_events: EventReader<AssetEvent<Sf2FileSource>>,
+    mut wav_events: EventReader<AssetEvent<WaveFileSource>>,
+    mut program_data: ResMut<ProgramAssets>,
+) {
+    let source_modified = midi_events
+        .read()
+        .any(|event| matches!(event, AssetEvent::Modified { .. }))
+        | sf2_events
+            .read()
+            .any(|event| matches!(event, AssetEvent::Modified { .. }))
+        | wav_events
+            .read()
+            .any(|event| matches!(event, AssetEvent::Modified { .. }));
+    for event in graph_events.read() {
+        for asset in program_data.programs.iter_mut() {
+            let reload = match asset.load_state {
+                LoadState::Loaded => event.is_modified(&asset.handle),
+                LoadState::Failed(_) => {
+                    event.is_modified(&asset.handle)
+                        || event.is_loaded_with_dependencies(&asset.handle)
+                }
+                _ => false,
+            };
+            if reload {
+                println!("RELOADING PROGRAM: {} ({})", asset.program_no, asset.name);
+                asset.load_state = LoadState::Loading;
+                asset.error = None;
+            }
+        }
+    }
+    if source_modified {
+        for asset in program_data.programs.iter_mut() {
+            if asset.load_state.is_loaded() {
+                asset.load_state = LoadState::Loading;
+            }
+        }
+    }
+}
+
+/// Check each program as its graph finishes loading. Programs are only
+/// stored for playing as part of the composite program of the output.
 fn check_graph_assets_ready(
     server: Res<AssetServer>,
     graph_assets: Res<Assets<MidiGraph>>,
-    midi_assets: Res<Assets<MidiFileSource>>,
-    sf2_assets: Res<Assets<Sf2FileSource>>,
-    wav_assets: Res<Assets<WaveFileSource>>,
     mut program_data: ResMut<ProgramAssets>,
-    mut audio_context: ResMut<MidiGraphAudioContext>,
-    mut events: EventWriter<StartProgramEvent>,
-    mut completed: Local<bool>,
+    mut failed_events: EventWriter<ProgramLoadFailedEvent>,
 ) {
-    if *completed {
-        return;
-    }
+    let program_data = program_data.as_mut();
     for asset in program_data.programs.iter_mut() {
-        match asset.0 {
+        match asset.load_state {
             LoadState::Loaded | LoadState::Failed(_) => continue,
             _ => {}
         };
-        let updated_load_state = server.load_state(asset.2.id());
+        let failure = match server.recursive_dependency_load_state(asset.handle.id()) {
+            RecursiveDependencyLoadState::Failed(error) => Some(error),
+            _ => None,
+        };
+        let updated_load_state = match (server.load_state(asset.handle.id()), failure) {
+            (LoadState::Loaded, Some(error)) => LoadState::Failed(error),
+            (load_state, _) => load_state,
+        };
         match updated_load_state {
-            LoadState::Failed(_) | LoadState::NotLoaded => {
-                asset.0 = updated_load_state;
-                continue;
+            LoadState::Failed(error) => {
+                warn!("Failed loading program {}: {}", asset.program_no, error);
+                asset.error = Some(ProgramLoadError::Asset(error.clone()));
+                asset.load_state = LoadState::Failed(error.clone());
+                failed_events.write(ProgramLoadFailedEvent {
+                    program_no: asset.program_no,
+                    error: ProgramLoadError::Asset(error),
+                });
             }
-            LoadState::Loaded | LoadState::Loading => {
-                let is_loaded = server.is_loaded_with_dependencies(asset.2.id());
-                if !is_loaded {
-                    return;
+            LoadState::NotLoaded | LoadState::Loading => {
+                asset.load_state = updated_load_state;
+            }
+            LoadState::Loaded => {
+                if !server.is_loaded_with_dependencies(asset.handle.id()) {
+                    asset.load_state = LoadState::Loading;
+                    continue;
+                }
+                let Some(graph) = graph_assets.get(&asset.handle) else {
+                    continue;
+                };
+                asset.load_state = LoadState::Loaded;
+                program_data.loads += 1;
+                match check_program(graph) {
+                    Ok(_) => {
+                        asset.error = None;
+                        println!("DID LOAD PROGRAM: {} ({})", asset.program_no, asset.name);
+                    }
+                    Err(error) => {
+                        let error = ProgramLoadError::Graph(error);
+                        warn!("Failed checking program {}: {}", asset.program_no, error);
+                        asset.error = Some(error.clone());
+                        failed_events.write(ProgramLoadFailedEvent {
+                            program_no: asset.program_no,
+                            error,
+                        });
+                    }
                 }
-                asset.0 = LoadState::Loaded;
-
-                let mut loader =
-                    GraphAssetLoader::new(&server, &midi_assets, &sf2_assets, &wav_assets);
-                let graph = graph_assets.get(&asset.2).unwrap();
-                audio_context
-                    .store_new_program(asset.1, &graph.config, &mut loader)
-                    .unwrap();
-                println!("DID STORE PROGRAM: {}", asset.1);
             }
         }
     }
-
-    *completed = true;
-    events.write(StartProgramEvent {
-        program_no: DEFAULT_PROGRAM,
-    });
 }
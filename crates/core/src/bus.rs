@@ -0,0 +1,326 @@
+use crate::{
+    KeyboardRegister,
+    effects::{DelayLine, Effects, EffectsChain, EffectsShared},
+    metronome::{Clicks, MetronomeClock},
+    mixer::Mixer,
+    wheels::Wheels,
+};
+use bevy::prelude::*;
+use bevy_midi_graph::midi::{
+    AssetLoader, Error, GenerateNode, Node, NodeConfig,
+    consts::{CHANNEL_COUNT, PLAYBACK_SAMPLE_RATE},
+    event::{Event, Message},
+    node::NodeConfigData,
+};
+use std::{
+    f32::consts::TAU,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+};
+
+/// A channel for each register and one for the nodes playing neither
+const CHANNELS: usize = 3;
+/// How far ahead of the bus notes are scheduled, which must outlast a frame
+pub(crate) const SCHEDULE_AHEAD_SECS: f64 = 0.05;
+const VIBRATO_HZ: f32 = 5.5;
+/// Sweeping the delay this far either side of its centre at the vibrato
+/// rate bends the pitch by about half a semitone
+const VIBRATO_DEPTH_SECS: f32 = 0.00085;
+/// How much of the way to a new modulation the vibrato moves each sample,
+/// so that turning the wheel does not crackle
+const MODULATION_SMOOTHING: f32 = 0.001;
+
+/// The output stage every program plays through, on the audio thread.
+/// The nodes playing each register are mixed at the register's gain and pan,
+/// so mixer changes reach notes that are already sounding. The modulation
+/// wheel adds vibrato to the mix, which then passes through the effects
+/// chain. Messages can be scheduled on the bus's clock, and are played at
+/// their sample rather than at the start of the next buffer. The metronome
+/// clicks on the beats of the same clock.
+#[derive(Resource, Default)]
+pub(crate) struct Bus {
+    shared: Arc<BusShared>,
+}
+
+impl Bus {
+    /// A program playing the given parts through the bus, each part being
+    /// the nodes of one register or, without one, of neither
+    pub fn program(
+        &self,
+        parts: Vec<(Option<KeyboardRegister>, NodeConfigData)>,
+        effects: &Effects,
+    ) -> NodeConfigData {
+        NodeConfigData(Box::new(BusConfig {
+            parts,
+            shared: self.shared.clone(),
+            effects: effects.shared(),
+        }))
+    }
+
+    /// Time played by the bus, counted in frames on the audio thread
+    pub fn audio_secs(&self) -> f64 {
+        self.shared.frames.load(Ordering::Relaxed) as f64 / PLAYBACK_SAMPLE_RATE as f64
+    }
+
+    /// Play a message at a time on the bus's clock, or as soon as possible if
+    /// that has passed
+    pub fn schedule(&self, secs: f64, message: Message) {
+        let frame = (secs.max(0.0) * PLAYBACK_SAMPLE_RATE as f64).round() as u64;
+        let Ok(mut scheduled) = self.shared.scheduled.lock() else {
+            warn!("Failed scheduling {:?}", message);
+            return;
+        };
+        // After any scheduled for the same frame, to keep them in order
+        let index = scheduled.partition_point(|(at, _)| *at <= frame);
+        scheduled.insert(index, (frame, message));
+    }
+
+    /// Drop the scheduled messages, except for the notes due to end, which
+    /// end at once so that none is left sounding
+    pub fn cancel_scheduled(&self) {
+        let Ok(mut scheduled) = self.shared.scheduled.lock() else {
+            warn!("Failed cancelling scheduled messages");
+            return;
+        };
+        scheduled.retain(|(_, message)| matches!(message.data, Event::NoteOff { .. }));
+        for (at, _) in scheduled.iter_mut() {
+            *at = 0;
+        }
+    }
+
+    /// The metronome settings the bus clicks to, and the clicks it has played
+    pub fn metronome(&self) -> &MetronomeClock {
+        &self.shared.metronome
+    }
+}
+
+/// Left and right gains of each channel and the modulation, shared with the
+/// audio thread as the bits of each `f32`, with the frames the bus has played
+/// and the messages scheduled for it in order, and the metronome
+struct BusShared {
+    gains: [[AtomicU32; 2]; CHANNELS],
+    modulation: AtomicU32,
+    frames: AtomicU64,
+    scheduled: Mutex<Vec<(u64, Message)>>,
+    metronome: MetronomeClock,
+}
+
+impl Default for BusShared {
+    fn default() -> Self {
+        Self {
+            gains: [(); CHANNELS].map(|_| [(); 2].map(|_| AtomicU32::new(1.0_f32.to_bits()))),
+            modulation: AtomicU32::new(0.0_f32.to_bits()),
+            frames: AtomicU64::new(0),
+            scheduled: Mutex::new(vec![]),
+            metronome: MetronomeClock::default(),
+        }
+    }
+}
+
+impl BusShared {
+    fn gains(&self, channel: usize) -> [f32; 2] {
+        self.gains[channel]
+            .each_ref()
+            .map(|gain| f32::from_bits(gain.load(Ordering::Relaxed)))
+    }
+
+    /// The scheduled messages due before a frame. Never waits on the main
+    /// thread, so a busy queue is tried again next buffer.
+    fn take_due(&self, end_frame: u64) -> Vec<(u64, Message)> {
+        let Ok(mut scheduled) = self.scheduled.try_lock() else {
+            return vec![];
+        };
+        let count = scheduled.partition_point(|(at, _)| *at < end_frame);
+        scheduled.drain(..count).collect()
+    }
+}
+
+fn channel_index(register: Option<KeyboardRegister>) -> usize {
+    match register {
+        Some(KeyboardRegister::Lower) => 0,
+        Some(KeyboardRegister::Upper) => 1,
+        None => 2,
+    }
+}
+
+struct BusConfig {
+    parts: Vec<(Option<KeyboardRegister>, NodeConfigData)>,
+    shared: Arc<BusShared>,
+    effects: Arc<EffectsShared>,
+}
+
+impl NodeConfig for BusConfig {
+    fn to_node(&self, asset_loader: &mut dyn AssetLoader) -> Result<GenerateNode, Error> {
+        let mut parts = Vec::with_capacity(self.parts.len());
+        for (register, config) in self.parts.iter() {
+            let channel = channel_index(*register);
+            parts.push(BusPart {
+                channel,
+                node: config.0.to_node(asset_loader)?,
+                gains: self.shared.gains(channel),
+            });
+        }
+        let mut chain = EffectsChain::new();
+        let mut effects_version = None;
+        self.effects.update(&mut chain, &mut effects_version);
+        Ok(Box::new(BusNode {
+            parts,
+            shared: self.shared.clone(),
+            chain,
+            effects: self.effects.clone(),
+            effects_version,
+            vibrato: Vibrato::new(),
+            clicks: Clicks::default(),
+            part_buffer: vec![],
+            mix_buffer: vec![],
+        }))
+    }
+}
+
+/// Vibrato made by sweeping the delay of a short line, which bends the pitch
+/// of everything passing through it
+struct Vibrato {
+    lines: [DelayLine; 2],
+    phase: f32,
+    modulation: f32,
+}
+
+impl Vibrato {
+    fn new() -> Self {
+        let length = (2.0 * VIBRATO_DEPTH_SECS * PLAYBACK_SAMPLE_RATE as f32) as usize + 4;
+        Self {
+            lines: [(); 2].map(|_| DelayLine::new(length)),
+            phase: 0.0,
+            modulation: 0.0,
+        }
+    }
+
+    fn process(&mut self, buffer: &mut [f32], modulation: f32) {
+        let sample_rate = PLAYBACK_SAMPLE_RATE as f32;
+        let step = TAU * VIBRATO_HZ / sample_rate;
+        for frame in buffer.chunks_exact_mut(CHANNEL_COUNT) {
+            self.modulation += (modulation - self.modulation) * MODULATION_SMOOTHING;
+            let delay_secs = VIBRATO_DEPTH_SECS * (1.0 + self.modulation * self.phase.sin());
+            for (sample, line) in frame.iter_mut().zip(self.lines.iter_mut()) {
+                line.write(*sample);
+                *sample = line.read(delay_secs * sample_rate);
+            }
+            self.phase = (self.phase + step) % TAU;
+        }
+    }
+}
+
+struct BusPart {
+    channel: usize,
+    node: GenerateNode,
+    /// The gains applied at the end of the last buffer
+    gains: [f32; 2],
+}
+
+struct BusNode {
+    parts: Vec<BusPart>,
+    shared: Arc<BusShared>,
+    chain: EffectsChain,
+    effects: Arc<EffectsShared>,
+    effects_version: Option<u64>,
+    vibrato: Vibrato,
+    clicks: Clicks,
+    part_buffer: Vec<f32>,
+    mix_buffer: Vec<f32>,
+}
+
+impl Node for BusNode {
+    fn on_event(&mut self, event: &Message) {
+        for part in self.parts.iter_mut() {
+            part.node.on_event(event);
+        }
+    }
+
+    fn fill_buffer(&mut self, buffer: &mut [f32]) {
+        self.effects
+            .update(&mut self.chain, &mut self.effects_version);
+        let start_frame = self.shared.frames.load(Ordering::Relaxed);
+        let frames = buffer.len() / CHANNEL_COUNT;
+        let mut filled = 0;
+        for (at, message) in self.shared.take_due(start_frame + frames as u64) {
+            let frame = (at.saturating_sub(start_frame) as usize).min(frames);
+            if frame > filled {
+                self.fill_span(&mut buffer[filled * CHANNEL_COUNT..frame * CHANNEL_COUNT]);
+                filled = frame;
+            }
+            self.on_event(&message);
+        }
+        if filled < frames {
+            self.fill_span(&mut buffer[filled * CHANNEL_COUNT..]);
+        }
+        self.clicks
+            .fill(&self.shared.metronome, buffer, start_frame);
+        self.shared
+            .frames
+            .fetch_add(frames as u64, Ordering::Relaxed);
+    }
+}
+
+impl BusNode {
+    /// Mix the parts into a span of the buffer, between scheduled messages
+    fn fill_span(&mut self, buffer: &mut [f32]) {
+        let frames = buffer.len() / CHANNEL_COUNT;
+        self.mix_buffer.clear();
+        self.mix_buffer.resize(buffer.len(), 0.0);
+        for part in self.parts.iter_mut() {
+            self.part_buffer.clear();
+            self.part_buffer.resize(buffer.len(), 0.0);
+            part.node.fill_buffer(&mut self.part_buffer);
+            // Changed gains are reached over the span so they do not click
+            let gains = self.shared.gains(part.channel);
+            let frame_pairs = self
+                .part_buffer
+                .chunks_exact(CHANNEL_COUNT)
+                .zip(self.mix_buffer.chunks_exact_mut(CHANNEL_COUNT));
+            for (index, (frame, mixed)) in frame_pairs.enumerate() {
+                let t = (index + 1) as f32 / frames as f32;
+                for (channel, (sample, mixed)) in frame.iter().zip(mixed.iter_mut()).enumerate() {
+                    let side = channel.min(1);
+                    let gain = part.gains[side] + (gains[side] - part.gains[side]) * t;
+                    *mixed += sample * gain;
+                }
+            }
+            part.gains = gains;
+        }
+        let modulation = f32::from_bits(self.shared.modulation.load(Ordering::Relaxed));
+        self.vibrato.process(&mut self.mix_buffer, modulation);
+        self.chain.process(&mut self.mix_buffer);
+        for (sample, mixed) in buffer.iter_mut().zip(self.mix_buffer.iter()) {
+            *sample += mixed;
+        }
+    }
+}
+
+/// Hand changed mixer levels to the bus on the audio thread
+pub(crate) fn share_mixer(mixer: Res<Mixer>, bus: Res<Bus>) {
+    if !mixer.is_changed() {
+        return;
+    }
+    for register in [
+        Some(KeyboardRegister::Lower),
+        Some(KeyboardRegister::Upper),
+        None,
+    ] {
+        let gains = mixer.output_gains(register);
+        for (shared, gain) in bus.shared.gains[channel_index(register)].iter().zip(gains) {
+            shared.store(gain.to_bits(), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Hand the position of the modulation wheel to the bus
+pub(crate) fn share_modulation(wheels: Res<Wheels>, bus: Res<Bus>) {
+    if !wheels.is_changed() {
+        return;
+    }
+    bus.shared
+        .modulation
+        .store(wheels.modulation.to_bits(), Ordering::Relaxed);
+}
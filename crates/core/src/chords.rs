@@ -0,0 +1,185 @@
+use crate::{
+    AllNotesOffEvent, KeyboardRegister,
+    input::make_key_event,
+    pipeline::{KeyPipeline, KeyPipelineSet},
+};
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_midi_graph::midi::event::Event;
+
+const CHORD_KEY: KeyCode = KeyCode::Backslash;
+
+pub struct ChordPlugin;
+
+impl Plugin for ChordPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChordMode>().add_systems(
+            PreUpdate,
+            (control_chord_mode, expand_chords)
+                .chain()
+                .in_set(KeyPipelineSet::Chord),
+        );
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChordKind {
+    #[default]
+    Major,
+    Minor,
+    Seventh,
+    Sus,
+    Custom,
+}
+
+impl ChordKind {
+    fn next(self) -> Self {
+        match self {
+            ChordKind::Major => ChordKind::Minor,
+            ChordKind::Minor => ChordKind::Seventh,
+            ChordKind::Seventh => ChordKind::Sus,
+            ChordKind::Sus => ChordKind::Custom,
+            ChordKind::Custom => ChordKind::Major,
+        }
+    }
+}
+
+/// Chords played from single keys of the lower register.
+/// Backslash turns chord mode on and off, and with Shift cycles the kind of
+/// chord. Holding the left Alt while pressing a key plays the first
+/// inversion, the right Alt the second and both the third. `custom` holds
+/// the semitones above the key of the custom voicing.
+#[derive(Resource)]
+pub struct ChordMode {
+    pub enabled: bool,
+    pub kind: ChordKind,
+    pub custom: Vec<u8>,
+    /// Notes played for each held key
+    held: HashMap<u8, Vec<u8>>,
+    /// How many held keys are playing each note
+    sounding: HashMap<u8, usize>,
+}
+
+impl Default for ChordMode {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            kind: ChordKind::Major,
+            custom: vec![0, 7, 11, 16],
+            held: HashMap::default(),
+            sounding: HashMap::default(),
+        }
+    }
+}
+
+impl ChordMode {
+    pub fn intervals(&self) -> &[u8] {
+        match self.kind {
+            ChordKind::Major => &[0, 4, 7],
+            ChordKind::Minor => &[0, 3, 7],
+            ChordKind::Seventh => &[0, 4, 7, 10],
+            ChordKind::Sus => &[0, 5, 7],
+            ChordKind::Custom => &self.custom,
+        }
+    }
+
+    /// Notes of the chord on a key, with its lowest notes raised an octave
+    /// for each inversion
+    fn voicing(&self, note: u8, inversion: usize) -> Vec<u8> {
+        let mut intervals = self.intervals().to_vec();
+        intervals.sort_unstable();
+        let inversion = inversion.min(intervals.len().saturating_sub(1));
+        for interval in intervals.iter_mut().take(inversion) {
+            *interval += 12;
+        }
+        intervals
+            .iter()
+            .filter_map(|interval| note.checked_add(*interval))
+            .filter(|note| *note < 128)
+            .collect()
+    }
+}
+
+fn control_chord_mode(inputs: Res<ButtonInput<KeyCode>>, mut chord_mode: ResMut<ChordMode>) {
+    if !inputs.just_pressed(CHORD_KEY) {
+        return;
+    }
+    if inputs.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        chord_mode.kind = chord_mode.kind.next();
+    } else {
+        chord_mode.enabled = !chord_mode.enabled;
+    }
+}
+
+/// Replace each lower-register key with the notes of its chord
+fn expand_chords(
+    mut all_notes_off: EventReader<AllNotesOffEvent>,
+    inputs: Res<ButtonInput<KeyCode>>,
+    mut chord_mode: ResMut<ChordMode>,
+    mut pipeline: ResMut<KeyPipeline>,
+) {
+    if !all_notes_off.is_empty() {
+        all_notes_off.clear();
+        chord_mode.held.clear();
+        chord_mode.sounding.clear();
+    }
+    if pipeline.events.is_empty() || (!chord_mode.enabled && chord_mode.held.is_empty()) {
+        return;
+    }
+    let inversion = match (
+        inputs.pressed(KeyCode::AltLeft),
+        inputs.pressed(KeyCode::AltRight),
+    ) {
+        (false, false) => 0,
+        (true, false) => 1,
+        (false, true) => 2,
+        (true, true) => 3,
+    };
+    // Held keys are not shown, so following them is not a change
+    let chord_mode = chord_mode.bypass_change_detection();
+    let mut events = Vec::with_capacity(pipeline.events.len());
+    for event in pipeline.events.drain(..) {
+        if event.register != KeyboardRegister::Lower {
+            events.push(event);
+            continue;
+        }
+        match event.message.data {
+            Event::NoteOn { note, vel } if chord_mode.enabled => {
+                let notes = chord_mode.voicing(note, inversion);
+                for chord_note in notes.iter() {
+                    let count = chord_mode.sounding.entry(*chord_note).or_default();
+                    *count += 1;
+                    if *count == 1 {
+                        events.push(make_key_event(
+                            event.register,
+                            Event::NoteOn {
+                                note: *chord_note,
+                                vel,
+                            },
+                        ));
+                    }
+                }
+                chord_mode.held.insert(note, notes);
+            }
+            Event::NoteOff { note, vel } if chord_mode.held.contains_key(&note) => {
+                for chord_note in chord_mode.held.remove(&note).unwrap_or_default() {
+                    let Some(count) = chord_mode.sounding.get_mut(&chord_note) else {
+                        continue;
+                    };
+                    *count -= 1;
+                    if *count == 0 {
+                        chord_mode.sounding.remove(&chord_note);
+                        events.push(make_key_event(
+                            event.register,
+                            Event::NoteOff {
+                                note: chord_note,
+                                vel,
+                            },
+                        ));
+                    }
+                }
+            }
+            _ => events.push(event),
+        }
+    }
+    pipeline.events = events;
+}
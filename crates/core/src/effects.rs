@@ -0,0 +1,759 @@
+use crate::{ActiveProgram, assets::ProgramAssets};
+use bevy::prelude::*;
+use bevy_midi_graph::midi::consts::{CHANNEL_COUNT, PLAYBACK_SAMPLE_RATE};
+use serde::{Deserialize, Serialize};
+use std::{
+    f32::consts::{FRAC_PI_2, TAU},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+const RESET_KEY: KeyCode = KeyCode::F4;
+const FILTER_KEY: KeyCode = KeyCode::F5;
+const CHORUS_KEY: KeyCode = KeyCode::F6;
+const DELAY_KEY: KeyCode = KeyCode::F7;
+const REVERB_KEY: KeyCode = KeyCode::F8;
+const COMPRESSOR_KEY: KeyCode = KeyCode::F9;
+const CHORUS_BASE_SECS: f32 = 0.012;
+const CHORUS_MAX_SECS: f32 = 0.05;
+const DELAY_MAX_SECS: f32 = 2.0;
+/// Freeverb's comb and allpass lengths, in samples at 44.1kHz
+const REVERB_COMB_SAMPLES: [usize; 4] = [1116, 1188, 1277, 1356];
+const REVERB_ALLPASS_SAMPLES: [usize; 2] = [556, 441];
+const REVERB_STEREO_SPREAD: usize = 23;
+const REVERB_INPUT_GAIN: f32 = 0.015;
+const COMPRESSOR_ATTACK_SECS: f32 = 0.005;
+const COMPRESSOR_RELEASE_SECS: f32 = 0.1;
+/// How long the delay and reverb ring on after being turned off
+const DELAY_TAIL_MAX_SECS: f32 = 20.0;
+const REVERB_TAIL_SECS: f32 = 5.0;
+/// The level repeats of the delay are heard down to
+const DELAY_TAIL_LEVEL: f32 = 0.001;
+
+pub struct EffectsPlugin;
+
+impl Plugin for EffectsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Effects>()
+            .add_systems(Update, control_effects)
+            .add_systems(PostUpdate, share_effects);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterKind {
+    #[default]
+    Off,
+    LowPass,
+    HighPass,
+}
+
+impl FilterKind {
+    fn next(self) -> Self {
+        match self {
+            FilterKind::Off => FilterKind::LowPass,
+            FilterKind::LowPass => FilterKind::HighPass,
+            FilterKind::HighPass => FilterKind::Off,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilterSettings {
+    pub kind: FilterKind,
+    pub cutoff_hz: f32,
+    pub resonance: f32,
+}
+
+impl Default for FilterSettings {
+    fn default() -> Self {
+        Self {
+            kind: FilterKind::Off,
+            cutoff_hz: 2000.0,
+            resonance: 0.707,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChorusSettings {
+    pub enabled: bool,
+    pub rate_hz: f32,
+    pub depth_secs: f32,
+    pub mix: f32,
+}
+
+impl Default for ChorusSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rate_hz: 0.8,
+            depth_secs: 0.004,
+            mix: 0.5,
+        }
+    }
+}
+
+/// A ping-pong delay, each repeat crossing to the other side
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DelaySettings {
+    pub enabled: bool,
+    pub time_secs: f32,
+    pub feedback: f32,
+    pub mix: f32,
+}
+
+impl Default for DelaySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            time_secs: 0.35,
+            feedback: 0.4,
+            mix: 0.3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReverbSettings {
+    pub enabled: bool,
+    pub room_size: f32,
+    pub damping: f32,
+    pub mix: f32,
+}
+
+impl Default for ReverbSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            room_size: 0.6,
+            damping: 0.4,
+            mix: 0.3,
+        }
+    }
+}
+
+/// Levels above `threshold` are reduced by `ratio`, which limits them at
+/// its highest
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressorSettings {
+    pub enabled: bool,
+    pub threshold: f32,
+    pub ratio: f32,
+}
+
+impl Default for CompressorSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold: 0.5,
+            ratio: 4.0,
+        }
+    }
+}
+
+/// The effects applied in order after the program: filter, chorus, delay,
+/// reverb and compressor. Programs may give their own in the manifest.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EffectsSettings {
+    pub filter: FilterSettings,
+    pub chorus: ChorusSettings,
+    pub delay: DelaySettings,
+    pub reverb: ReverbSettings,
+    pub compressor: CompressorSettings,
+}
+
+impl EffectsSettings {
+    pub fn is_active(&self) -> bool {
+        self.filter.kind != FilterKind::Off
+            || self.chorus.enabled
+            || self.delay.enabled
+            || self.reverb.enabled
+            || self.compressor.enabled
+    }
+}
+
+/// An effect setting that can be changed while playing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EffectParameter {
+    #[default]
+    FilterCutoff,
+    FilterResonance,
+    ChorusRate,
+    ChorusDepth,
+    ChorusMix,
+    DelayTime,
+    DelayFeedback,
+    DelayMix,
+    ReverbRoomSize,
+    ReverbDamping,
+    ReverbMix,
+    CompressorThreshold,
+    CompressorRatio,
+}
+
+impl EffectParameter {
+    const ALL: [EffectParameter; 13] = [
+        EffectParameter::FilterCutoff,
+        EffectParameter::FilterResonance,
+        EffectParameter::ChorusRate,
+        EffectParameter::ChorusDepth,
+        EffectParameter::ChorusMix,
+        EffectParameter::DelayTime,
+        EffectParameter::DelayFeedback,
+        EffectParameter::DelayMix,
+        EffectParameter::ReverbRoomSize,
+        EffectParameter::ReverbDamping,
+        EffectParameter::ReverbMix,
+        EffectParameter::CompressorThreshold,
+        EffectParameter::CompressorRatio,
+    ];
+
+    fn step(self, steps: isize) -> Self {
+        let index = Self::ALL
+            .iter()
+            .position(|parameter| *parameter == self)
+            .unwrap_or(0) as isize;
+        Self::ALL[(index + steps).rem_euclid(Self::ALL.len() as isize) as usize]
+    }
+
+    /// The lowest and highest values and the amount one step changes it by
+    fn range(self) -> (f32, f32, f32) {
+        match self {
+            EffectParameter::FilterCutoff => (40.0, 16000.0, 0.0),
+            EffectParameter::FilterResonance => (0.5, 8.0, 0.25),
+            EffectParameter::ChorusRate => (0.1, 5.0, 0.1),
+            EffectParameter::ChorusDepth => (0.0005, 0.01, 0.0005),
+            EffectParameter::DelayTime => (0.05, DELAY_MAX_SECS, 0.05),
+            EffectParameter::DelayFeedback => (0.0, 0.9, 0.05),
+            EffectParameter::CompressorThreshold => (0.05, 1.0, 0.05),
+            EffectParameter::CompressorRatio => (1.0, 20.0, 1.0),
+            _ => (0.0, 1.0, 0.05),
+        }
+    }
+
+    pub fn value(self, settings: &EffectsSettings) -> f32 {
+        match self {
+            EffectParameter::FilterCutoff => settings.filter.cutoff_hz,
+            EffectParameter::FilterResonance => settings.filter.resonance,
+            EffectParameter::ChorusRate => settings.chorus.rate_hz,
+            EffectParameter::ChorusDepth => settings.chorus.depth_secs,
+            EffectParameter::ChorusMix => settings.chorus.mix,
+            EffectParameter::DelayTime => settings.delay.time_secs,
+            EffectParameter::DelayFeedback => settings.delay.feedback,
+            EffectParameter::DelayMix => settings.delay.mix,
+            EffectParameter::ReverbRoomSize => settings.reverb.room_size,
+            EffectParameter::ReverbDamping => settings.reverb.damping,
+            EffectParameter::ReverbMix => settings.reverb.mix,
+            EffectParameter::CompressorThreshold => settings.compressor.threshold,
+            EffectParameter::CompressorRatio => settings.compressor.ratio,
+        }
+    }
+
+    fn value_mut(self, settings: &mut EffectsSettings) -> &mut f32 {
+        match self {
+            EffectParameter::FilterCutoff => &mut settings.filter.cutoff_hz,
+            EffectParameter::FilterResonance => &mut settings.filter.resonance,
+            EffectParameter::ChorusRate => &mut settings.chorus.rate_hz,
+            EffectParameter::ChorusDepth => &mut settings.chorus.depth_secs,
+            EffectParameter::ChorusMix => &mut settings.chorus.mix,
+            EffectParameter::DelayTime => &mut settings.delay.time_secs,
+            EffectParameter::DelayFeedback => &mut settings.delay.feedback,
+            EffectParameter::DelayMix => &mut settings.delay.mix,
+            EffectParameter::ReverbRoomSize => &mut settings.reverb.room_size,
+            EffectParameter::ReverbDamping => &mut settings.reverb.damping,
+            EffectParameter::ReverbMix => &mut settings.reverb.mix,
+            EffectParameter::CompressorThreshold => &mut settings.compressor.threshold,
+            EffectParameter::CompressorRatio => &mut settings.compressor.ratio,
+        }
+    }
+
+    /// Change the value by a number of steps. The cutoff steps by a fifth of
+    /// an octave or so, to sweep evenly by ear.
+    fn adjust(self, settings: &mut EffectsSettings, steps: f32) {
+        let (min, max, step) = self.range();
+        let value = self.value_mut(settings);
+        *value = match self {
+            EffectParameter::FilterCutoff => *value * 1.15_f32.powf(steps),
+            _ => *value + step * steps,
+        }
+        .clamp(min, max);
+    }
+}
+
+/// The effects chain on the output bus, after every program.
+/// F5 cycles the filter through off, low-pass and high-pass, and F6 to F9
+/// turn the chorus, delay, reverb and compressor on and off. Alt with the
+/// left and right arrows selects a parameter and with the up and down
+/// arrows changes it. F4 returns to the program's own effects. Any of these
+/// keys the manifest gives to a program selects the program instead.
+#[derive(Resource, Default)]
+pub struct Effects {
+    pub settings: EffectsSettings,
+    pub selected: EffectParameter,
+    shared: Arc<EffectsShared>,
+}
+
+impl Effects {
+    pub fn is_active(&self) -> bool {
+        self.settings.is_active()
+    }
+
+    pub(crate) fn shared(&self) -> Arc<EffectsShared> {
+        self.shared.clone()
+    }
+}
+
+/// Settings shared with the audio thread, counting each change so the chain
+/// knows when to pick them up
+#[derive(Default)]
+pub(crate) struct EffectsShared {
+    settings: Mutex<EffectsSettings>,
+    version: AtomicU64,
+}
+
+impl EffectsShared {
+    /// Bring a chain up to date with the latest settings. Never waits on the
+    /// main thread, so a busy lock is tried again next time.
+    pub fn update(&self, chain: &mut EffectsChain, chain_version: &mut Option<u64>) {
+        let version = self.version.load(Ordering::Relaxed);
+        if *chain_version == Some(version) {
+            return;
+        }
+        if let Ok(settings) = self.settings.try_lock() {
+            chain.set_settings(&settings);
+            *chain_version = Some(version);
+        }
+    }
+}
+
+/// A circular buffer of past samples
+pub(crate) struct DelayLine {
+    buffer: Vec<f32>,
+    position: usize,
+}
+
+impl DelayLine {
+    pub fn new(length: usize) -> Self {
+        Self {
+            buffer: vec![0.0; length.max(2)],
+            position: 0,
+        }
+    }
+
+    /// The sample written `delay` samples ago, between samples if fractional
+    pub fn read(&self, delay: f32) -> f32 {
+        let length = self.buffer.len();
+        let delay = delay.clamp(1.0, (length - 1) as f32);
+        let whole = delay as usize;
+        let fraction = delay - whole as f32;
+        let at = |samples_ago: usize| {
+            self.buffer[(self.position + length - samples_ago.min(length)) % length]
+        };
+        let newer = at(whole);
+        let older = at(whole + 1);
+        newer + (older - newer) * fraction
+    }
+
+    /// The sample about to be overwritten
+    fn oldest(&self) -> f32 {
+        self.buffer[self.position]
+    }
+
+    pub fn write(&mut self, sample: f32) {
+        self.buffer[self.position] = sample;
+        self.position = (self.position + 1) % self.buffer.len();
+    }
+
+    fn clear(&mut self) {
+        self.buffer.fill(0.0);
+    }
+}
+
+/// Biquad coefficients and the last two inputs and outputs of one channel
+#[derive(Default)]
+struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl Biquad {
+    fn set(&mut self, kind: FilterKind, cutoff_hz: f32, resonance: f32) {
+        let nyquist = PLAYBACK_SAMPLE_RATE as f32 / 2.0;
+        let w0 = TAU * cutoff_hz.min(nyquist * 0.95) / PLAYBACK_SAMPLE_RATE as f32;
+        let alpha = w0.sin() / (2.0 * resonance.max(0.1));
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+        let b = match kind {
+            FilterKind::HighPass => [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            _ => [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+        };
+        self.b = b.map(|coefficient| coefficient / a0);
+        self.a = [-2.0 * cos / a0, (1.0 - alpha) / a0];
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+
+    fn clear(&mut self) {
+        self.x = [0.0; 2];
+        self.y = [0.0; 2];
+    }
+}
+
+/// A feedback comb filter that darkens as it repeats
+struct Comb {
+    line: DelayLine,
+    filter_store: f32,
+}
+
+impl Comb {
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.line.oldest();
+        self.filter_store = output * (1.0 - damping) + self.filter_store * damping;
+        self.line.write(input + self.filter_store * feedback);
+        output
+    }
+}
+
+struct Allpass {
+    line: DelayLine,
+}
+
+impl Allpass {
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.line.oldest();
+        self.line.write(input + delayed * 0.5);
+        delayed - input
+    }
+}
+
+/// Reverb after Freeverb, with a set of combs and allpasses for each side
+struct Reverb {
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<Allpass>; 2],
+}
+
+impl Reverb {
+    fn new() -> Self {
+        let scale = PLAYBACK_SAMPLE_RATE as f32 / 44_100.0;
+        let samples = |samples: usize, side: usize| {
+            ((samples + side * REVERB_STEREO_SPREAD) as f32 * scale) as usize
+        };
+        let combs = |side: usize| -> Vec<Comb> {
+            REVERB_COMB_SAMPLES
+                .iter()
+                .map(|length| Comb {
+                    line: DelayLine::new(samples(*length, side)),
+                    filter_store: 0.0,
+                })
+                .collect()
+        };
+        let allpasses = |side: usize| -> Vec<Allpass> {
+            REVERB_ALLPASS_SAMPLES
+                .iter()
+                .map(|length| Allpass {
+                    line: DelayLine::new(samples(*length, side)),
+                })
+                .collect()
+        };
+        Self {
+            combs: [combs(0), combs(1)],
+            allpasses: [allpasses(0), allpasses(1)],
+        }
+    }
+
+    fn process(&mut self, input: f32, side: usize, settings: &ReverbSettings) -> f32 {
+        let feedback = 0.7 + 0.28 * settings.room_size;
+        let mut output = self.combs[side]
+            .iter_mut()
+            .map(|comb| comb.process(input, feedback, settings.damping))
+            .sum();
+        for allpass in self.allpasses[side].iter_mut() {
+            output = allpass.process(output);
+        }
+        output
+    }
+
+    fn clear(&mut self) {
+        for comb in self.combs.iter_mut().flatten() {
+            comb.line.clear();
+            comb.filter_store = 0.0;
+        }
+        for allpass in self.allpasses.iter_mut().flatten() {
+            allpass.line.clear();
+        }
+    }
+}
+
+/// The state of every effect, processing interleaved buffers of the
+/// program's output. The delay and reverb ring out after being turned off,
+/// and every effect starts again from silence when turned back on.
+pub(crate) struct EffectsChain {
+    settings: EffectsSettings,
+    filters: [Biquad; 2],
+    chorus_lines: [DelayLine; 2],
+    chorus_phase: f32,
+    delay_lines: [DelayLine; 2],
+    /// Frames the delay has left to ring out for
+    delay_tail: usize,
+    reverb: Reverb,
+    reverb_tail: usize,
+    compressor_envelope: f32,
+}
+
+impl EffectsChain {
+    pub fn new() -> Self {
+        let samples = |secs: f32| (secs * PLAYBACK_SAMPLE_RATE as f32) as usize + 2;
+        Self {
+            settings: EffectsSettings::default(),
+            filters: Default::default(),
+            chorus_lines: [(); 2].map(|_| DelayLine::new(samples(CHORUS_MAX_SECS))),
+            chorus_phase: 0.0,
+            delay_lines: [(); 2].map(|_| DelayLine::new(samples(DELAY_MAX_SECS))),
+            delay_tail: 0,
+            reverb: Reverb::new(),
+            reverb_tail: 0,
+            compressor_envelope: 0.0,
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: &EffectsSettings) {
+        let sample_rate = PLAYBACK_SAMPLE_RATE as f32;
+        let previous = std::mem::replace(&mut self.settings, settings.clone());
+        let settings = &self.settings;
+        if previous.filter.kind != FilterKind::Off && settings.filter.kind == FilterKind::Off {
+            self.filters.iter_mut().for_each(Biquad::clear);
+        }
+        if previous.chorus.enabled && !settings.chorus.enabled {
+            self.chorus_lines.iter_mut().for_each(DelayLine::clear);
+        }
+        if previous.delay.enabled && !settings.delay.enabled {
+            let feedback = previous.delay.feedback.clamp(0.01, 0.99);
+            let repeats = DELAY_TAIL_LEVEL.ln() / feedback.ln() + 1.0;
+            let tail_secs = (repeats * previous.delay.time_secs).min(DELAY_TAIL_MAX_SECS);
+            self.delay_tail = (tail_secs * sample_rate) as usize;
+        }
+        if settings.delay.enabled {
+            self.delay_tail = 0;
+        }
+        if previous.reverb.enabled && !settings.reverb.enabled {
+            self.reverb_tail = (REVERB_TAIL_SECS * sample_rate) as usize;
+        }
+        if settings.reverb.enabled {
+            self.reverb_tail = 0;
+        }
+        if !settings.compressor.enabled {
+            self.compressor_envelope = 0.0;
+        }
+        let filter = &settings.filter;
+        for biquad in self.filters.iter_mut() {
+            biquad.set(filter.kind, filter.cutoff_hz, filter.resonance);
+        }
+    }
+
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        if !self.settings.is_active() && self.delay_tail == 0 && self.reverb_tail == 0 {
+            return;
+        }
+        let sample_rate = PLAYBACK_SAMPLE_RATE as f32;
+        let settings = &self.settings;
+        let chorus_step = TAU * settings.chorus.rate_hz / sample_rate;
+        let delay_samples = settings.delay.time_secs * sample_rate;
+        let attack = (-1.0 / (COMPRESSOR_ATTACK_SECS * sample_rate)).exp();
+        let release = (-1.0 / (COMPRESSOR_RELEASE_SECS * sample_rate)).exp();
+        for frame in buffer.chunks_exact_mut(CHANNEL_COUNT) {
+            let mut sides = [frame[0], frame[CHANNEL_COUNT - 1]];
+            if settings.filter.kind != FilterKind::Off {
+                for (side, sample) in sides.iter_mut().enumerate() {
+                    *sample = self.filters[side].process(*sample);
+                }
+            }
+            if settings.chorus.enabled {
+                for (side, sample) in sides.iter_mut().enumerate() {
+                    // The sides sweep a quarter turn apart to widen the sound
+                    let sweep = (self.chorus_phase + side as f32 * FRAC_PI_2).sin();
+                    let delay_secs =
+                        CHORUS_BASE_SECS + settings.chorus.depth_secs * (1.0 + sweep) / 2.0;
+                    let line = &mut self.chorus_lines[side];
+                    line.write(*sample);
+                    let wet = line.read(delay_secs * sample_rate);
+                    *sample = *sample * (1.0 - settings.chorus.mix / 2.0)
+                        + wet * settings.chorus.mix / 2.0;
+                }
+                self.chorus_phase = (self.chorus_phase + chorus_step) % TAU;
+            }
+            if settings.delay.enabled || self.delay_tail > 0 {
+                // Turned off, the repeats carry on without taking in more
+                let input = match settings.delay.enabled {
+                    true => sides,
+                    false => [0.0; 2],
+                };
+                let repeats = [
+                    self.delay_lines[0].read(delay_samples),
+                    self.delay_lines[1].read(delay_samples),
+                ];
+                for (side, sample) in sides.iter_mut().enumerate() {
+                    self.delay_lines[side]
+                        .write(input[side] + repeats[1 - side] * settings.delay.feedback);
+                    *sample += repeats[side] * settings.delay.mix;
+                }
+            }
+            if settings.reverb.enabled || self.reverb_tail > 0 {
+                let input = match settings.reverb.enabled {
+                    true => (sides[0] + sides[1]) * REVERB_INPUT_GAIN,
+                    false => 0.0,
+                };
+                let dry = match settings.reverb.enabled {
+                    true => 1.0 - settings.reverb.mix,
+                    false => 1.0,
+                };
+                for (side, sample) in sides.iter_mut().enumerate() {
+                    let wet = self.reverb.process(input, side, &settings.reverb);
+                    *sample = *sample * dry + wet * settings.reverb.mix;
+                }
+            }
+            if settings.compressor.enabled {
+                let level = sides[0].abs().max(sides[1].abs());
+                let coefficient = match level > self.compressor_envelope {
+                    true => attack,
+                    false => release,
+                };
+                self.compressor_envelope = level + (self.compressor_envelope - level) * coefficient;
+                let threshold = settings.compressor.threshold.max(0.001);
+                if self.compressor_envelope > threshold {
+                    let compressed = threshold
+                        * (self.compressor_envelope / threshold)
+                            .powf(1.0 / settings.compressor.ratio.max(1.0));
+                    let gain = compressed / self.compressor_envelope;
+                    for sample in sides.iter_mut() {
+                        *sample *= gain;
+                    }
+                }
+            }
+            frame[0] = sides[0];
+            frame[CHANNEL_COUNT - 1] = sides[1];
+        }
+
+        // Once rung out, the lines are cleared so nothing old is heard when
+        // the effect is turned back on
+        let frames = buffer.len() / CHANNEL_COUNT;
+        if self.delay_tail > 0 {
+            self.delay_tail = self.delay_tail.saturating_sub(frames);
+            if self.delay_tail == 0 {
+                self.delay_lines.iter_mut().for_each(DelayLine::clear);
+            }
+        }
+        if self.reverb_tail > 0 {
+            self.reverb_tail = self.reverb_tail.saturating_sub(frames);
+            if self.reverb_tail == 0 {
+                self.reverb.clear();
+            }
+        }
+    }
+}
+
+fn control_effects(
+    inputs: Res<ButtonInput<KeyCode>>,
+    active_program: Res<ActiveProgram>,
+    programs: Res<ProgramAssets>,
+    mut effects: ResMut<Effects>,
+) {
+    let toggled = [
+        FILTER_KEY,
+        CHORUS_KEY,
+        DELAY_KEY,
+        REVERB_KEY,
+        COMPRESSOR_KEY,
+        RESET_KEY,
+    ]
+    .into_iter()
+    .find(|key| inputs.just_pressed(*key) && programs.program_for_key(key).is_none());
+    if let Some(key) = toggled {
+        let settings = &mut effects.settings;
+        match key {
+            FILTER_KEY => settings.filter.kind = settings.filter.kind.next(),
+            CHORUS_KEY => settings.chorus.enabled = !settings.chorus.enabled,
+            DELAY_KEY => settings.delay.enabled = !settings.delay.enabled,
+            REVERB_KEY => settings.reverb.enabled = !settings.reverb.enabled,
+            COMPRESSOR_KEY => settings.compressor.enabled = !settings.compressor.enabled,
+            _ => {
+                *settings = active_program
+                    .program_no
+                    .and_then(|program_no| programs.get(program_no))
+                    .map_or_else(EffectsSettings::default, |program| program.effects.clone())
+            }
+        }
+    }
+
+    if !inputs.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]) {
+        return;
+    }
+    if inputs.just_pressed(KeyCode::ArrowLeft) {
+        effects.selected = effects.selected.step(-1);
+    }
+    if inputs.just_pressed(KeyCode::ArrowRight) {
+        effects.selected = effects.selected.step(1);
+    }
+    let steps = match (
+        inputs.just_pressed(KeyCode::ArrowUp),
+        inputs.just_pressed(KeyCode::ArrowDown),
+    ) {
+        (true, false) => 1.0,
+        (false, true) => -1.0,
+        _ => return,
+    };
+    let selected = effects.selected;
+    selected.adjust(&mut effects.settings, steps);
+}
+
+/// Switch to the effects of a newly started program
+pub(crate) fn apply_program_effects(
+    active_program: Res<ActiveProgram>,
+    programs: Res<ProgramAssets>,
+    mut effects: ResMut<Effects>,
+    mut program_no: Local<Option<usize>>,
+) {
+    if active_program.program_no == *program_no {
+        return;
+    }
+    *program_no = active_program.program_no;
+    let Some(program) = active_program
+        .program_no
+        .and_then(|program_no| programs.get(program_no))
+    else {
+        return;
+    };
+    effects.settings = program.effects.clone();
+}
+
+/// Hand changed settings to the chain on the audio thread
+fn share_effects(effects: Res<Effects>) {
+    if !effects.is_changed() {
+        return;
+    }
+    match effects.shared.settings.lock() {
+        Ok(mut settings) => *settings = effects.settings.clone(),
+        Err(error) => {
+            warn!("Failed sharing effects: {}", error);
+            return;
+        }
+    }
+    effects.shared.version.fetch_add(1, Ordering::Relaxed);
+}
@@ -0,0 +1,243 @@
+use crate::{
+    ActiveProgram, KeyEvent, KeySource, KeyboardRegister,
+    input::{make_key_event, node_id_for},
+    mixer::Mixer,
+};
+use bevy::prelude::*;
+use bevy_midi_graph::{
+    MidiGraph,
+    midi::{
+        event::{Event, EventTarget, Message},
+        node::NodeConfigData,
+    },
+};
+use serde_json::{Value, json};
+use std::collections::BTreeSet;
+
+/// Node ids of each program in the ensemble are offset by its program
+/// number times this, so programs with node ids this large are rejected
+const NODE_ID_STRIDE: u64 = 1000;
+
+/// Programs that sound alongside the active program, for the looper and
+/// for the registers.
+/// They are combined with it into one composite program, each with its
+/// node ids offset so that events can be sent to it alone.
+#[derive(Resource, Default)]
+pub(crate) struct Ensemble {
+    pub looped: BTreeSet<usize>,
+    pub registers: BTreeSet<usize>,
+}
+
+impl Ensemble {
+    pub fn programs(&self) -> BTreeSet<usize> {
+        self.looped.union(&self.registers).copied().collect()
+    }
+}
+
+/// The JSON of a loaded program graph, for combining into a composite
+/// program
+pub(crate) fn program_source(graph: &MidiGraph) -> Result<Value, String> {
+    serde_json::to_value(&graph.config).map_err(|error| error.to_string())
+}
+
+/// Check that a loaded program graph can be combined into a composite
+/// program: that it reads back from its JSON, and that its node ids are too
+/// small to reach those of another program once offset
+pub(crate) fn check_program(graph: &MidiGraph) -> Result<(), String> {
+    let source = program_source(graph)?;
+    let mut node_ids = vec![];
+    collect_node_ids(&source, &mut node_ids);
+    if let Some(node_id) = node_ids.iter().find(|node_id| **node_id >= NODE_ID_STRIDE) {
+        return Err(format!(
+            "Node id {} is not below the limit of {}",
+            node_id, NODE_ID_STRIDE
+        ));
+    }
+    serde_json::from_value::<NodeConfigData>(source)
+        .map(|_| ())
+        .map_err(|error| error.to_string())
+}
+
+fn ensemble_node_id(program_no: usize, node_id: u64) -> u64 {
+    program_no as u64 * NODE_ID_STRIDE + node_id
+}
+
+/// A key event for a program of the ensemble rather than the active program
+pub(crate) fn make_ensemble_key_event(
+    program_no: usize,
+    register: KeyboardRegister,
+    source: KeySource,
+    data: Event,
+) -> KeyEvent {
+    let mut event = make_key_event(register, data);
+    event.source = source;
+    event.message.target =
+        EventTarget::SpecificNode(ensemble_node_id(program_no, node_id_for(register)));
+    event
+}
+
+/// The messages that play a key event. Keys other than looped ones play the
+/// program of their register and every program layered on it, at the
+/// register's layer gain.
+pub(crate) fn route_key_event(
+    active_program: &ActiveProgram,
+    mixer: &Mixer,
+    event: &KeyEvent,
+) -> Vec<Message> {
+    if event.source == KeySource::Loop {
+        return vec![event.message.clone()];
+    }
+    let split_program = match event.register {
+        KeyboardRegister::Lower => active_program.lower,
+        KeyboardRegister::Upper => active_program.upper,
+    };
+    let mut messages = vec![match split_program {
+        Some(program_no) => {
+            make_ensemble_key_event(program_no, event.register, event.source, event.data.clone())
+                .message
+        }
+        None => event.message.clone(),
+    }];
+    let layer_gain = mixer.strip(event.register).layer_gain;
+    for layer in active_program
+        .layers
+        .iter()
+        .filter(|layer| layer.register == event.register)
+    {
+        let data = match event.data {
+            Event::NoteOn { note, vel } => Event::NoteOn {
+                note,
+                vel: vel * layer_gain,
+            },
+            ref data => data.clone(),
+        };
+        messages.push(
+            make_ensemble_key_event(layer.program_no, event.register, event.source, data).message,
+        );
+    }
+    messages
+}
+
+/// The programs the registers play besides the active program
+pub(crate) fn register_programs(active_program: &ActiveProgram) -> BTreeSet<usize> {
+    [active_program.lower, active_program.upper]
+        .into_iter()
+        .flatten()
+        .chain(active_program.layers.iter().map(|layer| layer.program_no))
+        .collect()
+}
+
+/// Combine the active program with the ensemble programs, offsetting the
+/// node ids of each ensemble program, and group their nodes into the parts
+/// of the output bus: the nodes playing each register and the rest
+pub(crate) fn compose_program(
+    active: &Value,
+    ensemble: &[(usize, Value)],
+) -> Vec<(Option<KeyboardRegister>, Value)> {
+    let mut parts: [(Option<KeyboardRegister>, Vec<Value>); 3] = [
+        (Some(KeyboardRegister::Lower), vec![]),
+        (Some(KeyboardRegister::Upper), vec![]),
+        (None, vec![]),
+    ];
+    let programs = std::iter::once((0, active.clone())).chain(
+        ensemble
+            .iter()
+            .map(|(program_no, source)| (ensemble_node_id(*program_no, 0), source.clone())),
+    );
+    for (offset, mut config) in programs {
+        offset_node_ids(&mut config, offset);
+        for node in program_nodes(config) {
+            let register = node_register(&node, offset);
+            if let Some((_, nodes)) = parts.iter_mut().find(|(part, _)| *part == register) {
+                nodes.push(node);
+            }
+        }
+    }
+    parts
+        .into_iter()
+        .filter(|(_, nodes)| !nodes.is_empty())
+        .map(|(register, nodes)| {
+            let config = json!({
+                "type": "Combiner",
+                "sources": nodes,
+            });
+            (register, config)
+        })
+        .collect()
+}
+
+/// The nodes a program combines at its top, or else the program itself
+fn program_nodes(config: Value) -> Vec<Value> {
+    match config {
+        Value::Object(mut map)
+            if map.len() == 2 && map.get("type").and_then(Value::as_str) == Some("Combiner") =>
+        {
+            match map.remove("sources") {
+                Some(Value::Array(sources)) => sources,
+                Some(sources) => {
+                    map.insert("sources".to_owned(), sources);
+                    vec![Value::Object(map)]
+                }
+                None => vec![Value::Object(map)],
+            }
+        }
+        config => vec![config],
+    }
+}
+
+/// The register played by a node of a program whose node ids are offset by
+/// `offset`, if the node or those below it play one register alone
+fn node_register(node: &Value, offset: u64) -> Option<KeyboardRegister> {
+    let mut node_ids = vec![];
+    collect_node_ids(node, &mut node_ids);
+    let plays = |register| node_ids.contains(&(offset + node_id_for(register)));
+    match (
+        plays(KeyboardRegister::Lower),
+        plays(KeyboardRegister::Upper),
+    ) {
+        (true, false) => Some(KeyboardRegister::Lower),
+        (false, true) => Some(KeyboardRegister::Upper),
+        _ => None,
+    }
+}
+
+fn collect_node_ids(value: &Value, node_ids: &mut Vec<u64>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter() {
+                match (key.as_str(), value.as_u64()) {
+                    ("node_id", Some(node_id)) => node_ids.push(node_id),
+                    _ => collect_node_ids(value, node_ids),
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values.iter() {
+                collect_node_ids(value, node_ids);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn offset_node_ids(value: &mut Value, offset: u64) {
+    if offset == 0 {
+        return;
+    }
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                match (key.as_str(), value.as_u64()) {
+                    ("node_id", Some(node_id)) => *value = json!(node_id + offset),
+                    _ => offset_node_ids(value, offset),
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values.iter_mut() {
+                offset_node_ids(value, offset);
+            }
+        }
+        _ => {}
+    }
+}
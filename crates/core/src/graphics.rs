@@ -1,8 +1,11 @@
 use crate::{
-    KeyEvent, KeyboardRegister, Settings,
-    utils::{make_note, note_is_black},
+    ActiveProgram, AllNotesOffEvent, KeyEvent, KeySource, KeyboardRegister, Settings,
+    keymap::KeyBindings,
+    pedal::Pedals,
+    practice::WrongNoteEvent,
+    scale::{ScaleLock, ScaleLockMode},
 };
-use bevy::prelude::*;
+use bevy::{picking::Pickable, prelude::*};
 use bevy_midi_graph::midi::event::{Event, Message};
 
 pub struct GraphicsPlugin;
@@ -12,24 +15,99 @@ const BASE_MARGIN: f32 = 0.1;
 const KEY_WIDTH: f32 = 0.2;
 const KEY_GAP: f32 = 0.02;
 const KEY_DEPTH: f32 = 0.8;
-const KEY_HEIGHT: f32 = 0.1;
+pub(crate) const KEY_HEIGHT: f32 = 0.1;
 const KEY_BLACK_DEPTH: f32 = 0.5;
 const ROW_ELEVATION: f32 = 0.3;
 const ROW_OFFSET: f32 = 0.6;
 const KEY_DEPRESSION: f32 = 0.05;
+const VELOCITY_LEVELS: usize = 5;
+const WRONG_NOTE_FLASH_SECS: f32 = 0.4;
+const LABEL_FONT_SIZE: f32 = 13.0;
+/// How far back from a key's front edge its label sits
+const LABEL_INSET: f32 = 0.1;
 
 #[derive(Component)]
-struct KeyWithNote {
-    note: u8,
-    register: KeyboardRegister,
+pub(crate) struct KeyWithNote {
+    pub note: u8,
+    pub register: KeyboardRegister,
+    white_key_advance: usize,
+    pub black_key: bool,
+    out_of_scale: bool,
 }
 
+impl KeyWithNote {
+    fn new(
+        settings: &Settings,
+        register: KeyboardRegister,
+        white_key_advance: usize,
+        black_key: bool,
+    ) -> Option<Self> {
+        settings
+            .note_for(register, white_key_advance, black_key)
+            .map(|note| Self {
+                note,
+                register,
+                white_key_advance,
+                black_key,
+                out_of_scale: false,
+            })
+    }
+
+    /// How far the key reaches back from its front edge
+    pub fn depth(&self) -> f32 {
+        match self.black_key {
+            true => KEY_BLACK_DEPTH,
+            false => KEY_DEPTH,
+        }
+    }
+}
+
+/// A key flashing after a wrong note was played on it
+#[derive(Component)]
+struct WrongNoteFlash(Timer);
+
+/// Position of a key when it is not being played
+#[derive(Component)]
+pub(crate) struct RestTranslation(pub Vec3);
+
+/// Text showing the keymap's label for a key, kept over it on screen
+#[derive(Component)]
+struct KeyLabel(Entity);
+
 #[derive(Resource, Default)]
 struct PianoMaterials {
     pub plastic: Handle<StandardMaterial>,
-    pub ivory: Handle<StandardMaterial>,
+    pub lower_ivory: Handle<StandardMaterial>,
+    pub upper_ivory: Handle<StandardMaterial>,
     pub ebony: Handle<StandardMaterial>,
-    pub illuminated: Handle<StandardMaterial>,
+    pub illuminated: Vec<Handle<StandardMaterial>>,
+    pub sustained: Handle<StandardMaterial>,
+    pub wrong: Handle<StandardMaterial>,
+    pub looped: Handle<StandardMaterial>,
+    pub dimmed: Handle<StandardMaterial>,
+}
+
+impl PianoMaterials {
+    fn at_rest(&self, key: &KeyWithNote) -> Handle<StandardMaterial> {
+        match (key.out_of_scale, key.black_key) {
+            (true, _) => self.dimmed.clone(),
+            (false, true) => self.ebony.clone(),
+            (false, false) => self.ivory(key.register),
+        }
+    }
+
+    /// White keys are tinted by the program their register plays
+    fn ivory(&self, register: KeyboardRegister) -> Handle<StandardMaterial> {
+        match register {
+            KeyboardRegister::Lower => self.lower_ivory.clone(),
+            KeyboardRegister::Upper => self.upper_ivory.clone(),
+        }
+    }
+
+    fn illuminated_for_velocity(&self, vel: f32) -> Handle<StandardMaterial> {
+        let level = (vel.clamp(0.0, 1.0) * (VELOCITY_LEVELS - 1) as f32).round() as usize;
+        self.illuminated[level].clone()
+    }
 }
 
 impl Plugin for GraphicsPlugin {
@@ -41,7 +119,21 @@ impl Plugin for GraphicsPlugin {
         })
         .insert_resource(PianoMaterials::default())
         .add_systems(Startup, create_piano)
-        .add_systems(Update, highlight_key_events);
+        .add_systems(
+            Update,
+            (
+                highlight_key_events,
+                reset_keys,
+                highlight_sustained_keys,
+                flash_wrong_notes,
+                relabel_keys,
+                dim_out_of_scale_keys,
+                tint_registers,
+                show_key_labels,
+                place_key_labels,
+            )
+                .chain(),
+        );
     }
 }
 
@@ -56,16 +148,42 @@ fn create_piano(
         base_color: Color::srgb(0.8, 0.8, 0.8).into(),
         ..default()
     });
-    materials.ivory = material_assets.add(StandardMaterial {
-        base_color: Color::srgb(0.9, 0.9, 0.9).into(),
+    materials.lower_ivory = material_assets.add(StandardMaterial {
+        base_color: program_tint(None),
+        ..default()
+    });
+    materials.upper_ivory = material_assets.add(StandardMaterial {
+        base_color: program_tint(None),
         ..default()
     });
     materials.ebony = material_assets.add(StandardMaterial {
         base_color: Color::srgb(0.2, 0.2, 0.2).into(),
         ..default()
     });
-    materials.illuminated = material_assets.add(StandardMaterial {
-        base_color: Color::srgb(0.8, 0.4, 0.4).into(),
+    materials.illuminated = (0..VELOCITY_LEVELS)
+        .map(|level| {
+            let t = level as f32 / (VELOCITY_LEVELS - 1) as f32;
+            material_assets.add(StandardMaterial {
+                base_color: Color::srgb(0.85 + 0.1 * t, 0.7 - 0.5 * t, 0.7 - 0.5 * t).into(),
+                ..default()
+            })
+        })
+        .collect();
+    materials.sustained = material_assets.add(StandardMaterial {
+        base_color: Color::srgb(0.6, 0.5, 0.85).into(),
+        ..default()
+    });
+    materials.wrong = material_assets.add(StandardMaterial {
+        base_color: Color::srgb(0.9, 0.1, 0.1).into(),
+        ..default()
+    });
+    materials.looped = material_assets.add(StandardMaterial {
+        base_color: Color::srgb(0.2, 0.8, 0.85).into(),
+        ..default()
+    });
+    materials.dimmed = material_assets.add(StandardMaterial {
+        base_color: Color::srgba(0.4, 0.4, 0.4, 0.35),
+        alpha_mode: AlphaMode::Blend,
         ..default()
     });
 
@@ -76,8 +194,7 @@ fn create_piano(
     let base_width = 12.0 * KEY_WIDTH + 11.0 * KEY_GAP + 2.0 * BASE_MARGIN;
     let base_depth = KEY_DEPTH + ROW_OFFSET + 2.0 * BASE_MARGIN;
     new_cube(
-        255,
-        KeyboardRegister::Lower,
+        (),
         &mut commands,
         materials.plastic.clone(),
         &mut mesh_assets,
@@ -88,20 +205,20 @@ fn create_piano(
     for i in 0..10 {
         let x =
             -0.5 * base_width + BASE_MARGIN + KEY_WIDTH + (i as f32 + 1.0) * (KEY_WIDTH + KEY_GAP);
-        let note = make_note(settings.note_on_z, i, false).expect("Failed getting white note");
-        new_cube(
-            note,
-            KeyboardRegister::Lower,
+        let key = KeyWithNote::new(&settings, KeyboardRegister::Lower, i, false)
+            .expect("Failed getting white note");
+        let entity = new_cube(
+            key,
             &mut commands,
-            materials.ivory.clone(),
+            materials.lower_ivory.clone(),
             &mut mesh_assets,
             Vec3::new(x, 0.5 * KEY_HEIGHT, -BASE_MARGIN - 0.5 * KEY_DEPTH),
             Vec3::new(KEY_WIDTH, KEY_HEIGHT, KEY_DEPTH),
         );
-        if let Some(note) = make_note(settings.note_on_z, i, true) {
-            new_cube(
-                note,
-                KeyboardRegister::Lower,
+        new_key_label(&mut commands, entity, false);
+        if let Some(key) = KeyWithNote::new(&settings, KeyboardRegister::Lower, i, true) {
+            let entity = new_cube(
+                key,
                 &mut commands,
                 materials.ebony.clone(),
                 &mut mesh_assets,
@@ -112,17 +229,18 @@ fn create_piano(
                 ),
                 Vec3::new(KEY_WIDTH, KEY_HEIGHT, KEY_BLACK_DEPTH),
             );
+            new_key_label(&mut commands, entity, true);
         }
     }
 
     for i in 0..12 {
         let x = -0.5 * base_width + BASE_MARGIN + KEY_WIDTH + (i as f32) * (KEY_WIDTH + KEY_GAP);
-        let note = make_note(settings.note_on_q, i, false).expect("Failed getting white note");
-        new_cube(
-            note,
-            KeyboardRegister::Upper,
+        let key = KeyWithNote::new(&settings, KeyboardRegister::Upper, i, false)
+            .expect("Failed getting white note");
+        let entity = new_cube(
+            key,
             &mut commands,
-            materials.ivory.clone(),
+            materials.upper_ivory.clone(),
             &mut mesh_assets,
             Vec3::new(
                 x,
@@ -131,10 +249,10 @@ fn create_piano(
             ),
             Vec3::new(KEY_WIDTH, KEY_HEIGHT, KEY_DEPTH),
         );
-        if let Some(note) = make_note(settings.note_on_q, i, true) {
-            new_cube(
-                note,
-                KeyboardRegister::Upper,
+        new_key_label(&mut commands, entity, false);
+        if let Some(key) = KeyWithNote::new(&settings, KeyboardRegister::Upper, i, true) {
+            let entity = new_cube(
+                key,
                 &mut commands,
                 materials.ebony.clone(),
                 &mut mesh_assets,
@@ -145,24 +263,47 @@ fn create_piano(
                 ),
                 Vec3::new(KEY_WIDTH, KEY_HEIGHT, KEY_BLACK_DEPTH),
             );
+            new_key_label(&mut commands, entity, true);
         }
     }
 }
 
 fn new_cube(
-    note: u8,
-    register: KeyboardRegister,
+    key: impl Bundle,
     commands: &mut Commands,
     material: Handle<StandardMaterial>,
     mesh_assets: &mut ResMut<Assets<Mesh>>,
     position: Vec3,
     size: Vec3,
-) {
+) -> Entity {
+    commands
+        .spawn((
+            key,
+            Mesh3d(mesh_assets.add(Cuboid::from_size(size))),
+            MeshMaterial3d(material),
+            Transform::from_translation(position),
+            RestTranslation(position),
+        ))
+        .id()
+}
+
+/// Label a key with the keyboard key that plays it, dark on white keys and
+/// light on black ones
+fn new_key_label(commands: &mut Commands, key: Entity, black_key: bool) {
+    let color = match black_key {
+        true => Color::srgb(0.9, 0.9, 0.9),
+        false => Color::srgb(0.2, 0.2, 0.2),
+    };
     commands.spawn((
-        KeyWithNote { note, register },
-        Mesh3d(mesh_assets.add(Cuboid::from_size(size))),
-        MeshMaterial3d(material),
-        Transform::from_translation(position),
+        KeyLabel(key),
+        Text::default(),
+        TextFont::from_font_size(LABEL_FONT_SIZE),
+        TextColor(color),
+        Node {
+            position_type: PositionType::Absolute,
+            ..default()
+        },
+        Pickable::IGNORE,
     ));
 }
 
@@ -171,38 +312,227 @@ fn highlight_key_events(
     materials: Res<PianoMaterials>,
     mut key_query: Query<(
         &KeyWithNote,
+        &RestTranslation,
         &mut MeshMaterial3d<StandardMaterial>,
         &mut Transform,
     )>,
 ) {
     for event in events.read() {
-        let (note, is_note_on) = match event.message {
+        let (note, note_on_vel) = match event.message {
             Message {
-                data: Event::NoteOn { note, .. },
+                data: Event::NoteOn { note, vel },
                 ..
-            } => (note, true),
+            } => (note, Some(vel)),
             Message {
                 data: Event::NoteOff { note, .. },
                 ..
-            } => (note, false),
+            } => (note, None),
             _ => {
                 continue;
             }
         };
-        if let Some((_, mut material, mut transform)) = key_query
+        if let Some((key, rest, mut material, mut transform)) = key_query
             .iter_mut()
             .find(|k| k.0.note == note && k.0.register == event.register)
         {
-            if is_note_on {
-                material.0 = materials.illuminated.clone();
+            transform.translation = rest.0;
+            if let Some(vel) = note_on_vel {
+                material.0 = match event.source {
+                    KeySource::Live | KeySource::Arp | KeySource::Song => {
+                        materials.illuminated_for_velocity(vel)
+                    }
+                    KeySource::Loop => materials.looped.clone(),
+                };
                 transform.translation.y -= KEY_DEPRESSION;
             } else {
-                material.0 = match note_is_black(note) {
-                    true => materials.ebony.clone(),
-                    false => materials.ivory.clone(),
-                };
-                transform.translation.y += KEY_DEPRESSION;
+                material.0 = materials.at_rest(key);
             }
         }
     }
 }
+
+fn reset_keys(
+    mut events: EventReader<AllNotesOffEvent>,
+    materials: Res<PianoMaterials>,
+    mut key_query: Query<(
+        &KeyWithNote,
+        &RestTranslation,
+        &mut MeshMaterial3d<StandardMaterial>,
+        &mut Transform,
+    )>,
+) {
+    if events.is_empty() {
+        return;
+    }
+    events.clear();
+    for (key, rest, mut material, mut transform) in key_query.iter_mut() {
+        material.0 = materials.at_rest(key);
+        transform.translation = rest.0;
+    }
+}
+
+fn highlight_sustained_keys(
+    pedals: Res<Pedals>,
+    materials: Res<PianoMaterials>,
+    mut key_query: Query<(&KeyWithNote, &mut MeshMaterial3d<StandardMaterial>)>,
+) {
+    if !pedals.is_changed() {
+        return;
+    }
+    for (key, mut material) in key_query.iter_mut() {
+        if pedals.is_sustained(key.register, key.note) {
+            material.0 = materials.sustained.clone();
+        }
+    }
+}
+
+fn flash_wrong_notes(
+    mut commands: Commands,
+    mut events: EventReader<WrongNoteEvent>,
+    time: Res<Time>,
+    materials: Res<PianoMaterials>,
+    mut key_query: Query<(
+        Entity,
+        &KeyWithNote,
+        &RestTranslation,
+        &Transform,
+        &mut MeshMaterial3d<StandardMaterial>,
+        Option<&mut WrongNoteFlash>,
+    )>,
+) {
+    for event in events.read() {
+        if let Some((entity, _, _, _, mut material, _)) = key_query
+            .iter_mut()
+            .find(|k| k.1.note == event.note && k.1.register == event.register)
+        {
+            material.0 = materials.wrong.clone();
+            commands
+                .entity(entity)
+                .insert(WrongNoteFlash(Timer::from_seconds(
+                    WRONG_NOTE_FLASH_SECS,
+                    TimerMode::Once,
+                )));
+        }
+    }
+    for (entity, key, rest, transform, mut material, flash) in key_query.iter_mut() {
+        let Some(mut flash) = flash else {
+            continue;
+        };
+        if !flash.0.tick(time.delta()).finished() {
+            continue;
+        }
+        commands.entity(entity).remove::<WrongNoteFlash>();
+        // A key still held down returns to rest when it is released
+        if transform.translation == rest.0 {
+            material.0 = materials.at_rest(key);
+        }
+    }
+}
+
+fn show_key_labels(
+    key_bindings: Res<KeyBindings>,
+    key_query: Query<&KeyWithNote>,
+    mut label_query: Query<(&KeyLabel, &mut Text)>,
+) {
+    if !key_bindings.is_changed() {
+        return;
+    }
+    for (label, mut text) in label_query.iter_mut() {
+        let Ok(key) = key_query.get(label.0) else {
+            continue;
+        };
+        text.0 = key_bindings
+            .label_for(key.register, key.white_key_advance, key.black_key)
+            .unwrap_or_default()
+            .to_owned();
+    }
+}
+
+/// Keep each label over the front of its key, which moves on screen as the
+/// key is played or the window is resized
+fn place_key_labels(
+    camera: Single<(&Camera, &GlobalTransform)>,
+    key_query: Query<(&KeyWithNote, &GlobalTransform)>,
+    mut label_query: Query<(&KeyLabel, &mut Node)>,
+) {
+    let (camera, camera_transform) = camera.into_inner();
+    for (label, mut node) in label_query.iter_mut() {
+        let Ok((key, transform)) = key_query.get(label.0) else {
+            continue;
+        };
+        let front = Vec3::new(0.0, 0.5 * KEY_HEIGHT, 0.5 * key.depth() - LABEL_INSET);
+        let Ok(position) =
+            camera.world_to_viewport(camera_transform, transform.transform_point(front))
+        else {
+            continue;
+        };
+        // Centred on the point, roughly, for a character or two
+        let left = Val::Px(position.x - 0.3 * LABEL_FONT_SIZE);
+        let top = Val::Px(position.y - 0.6 * LABEL_FONT_SIZE);
+        if node.left != left || node.top != top {
+            node.left = left;
+            node.top = top;
+        }
+    }
+}
+
+fn relabel_keys(settings: Res<Settings>, mut key_query: Query<&mut KeyWithNote>) {
+    if !settings.is_changed() {
+        return;
+    }
+    for mut key in key_query.iter_mut() {
+        if let Some(note) = settings.note_for(key.register, key.white_key_advance, key.black_key) {
+            key.note = note;
+        }
+    }
+}
+
+/// Dim the keys whose notes are outside the locked scale
+fn dim_out_of_scale_keys(
+    settings: Res<Settings>,
+    scale_lock: Res<ScaleLock>,
+    materials: Res<PianoMaterials>,
+    mut key_query: Query<(
+        &mut KeyWithNote,
+        &RestTranslation,
+        &Transform,
+        &mut MeshMaterial3d<StandardMaterial>,
+    )>,
+) {
+    if !settings.is_changed() && !scale_lock.is_changed() {
+        return;
+    }
+    for (mut key, rest, transform, mut material) in key_query.iter_mut() {
+        let out_of_scale = scale_lock.mode != ScaleLockMode::Off && !scale_lock.in_scale(key.note);
+        if key.out_of_scale == out_of_scale {
+            continue;
+        }
+        key.out_of_scale = out_of_scale;
+        if transform.translation == rest.0 {
+            material.0 = materials.at_rest(&key);
+        }
+    }
+}
+
+/// Ivory with a hue of its own for each program
+fn program_tint(program_no: Option<usize>) -> Color {
+    match program_no {
+        Some(program_no) => Color::hsl((program_no as f32 * 137.5) % 360.0, 0.5, 0.88),
+        None => Color::srgb(0.9, 0.9, 0.9),
+    }
+}
+
+fn tint_registers(
+    active_program: Res<ActiveProgram>,
+    materials: Res<PianoMaterials>,
+    mut material_assets: ResMut<Assets<StandardMaterial>>,
+) {
+    if !active_program.is_changed() {
+        return;
+    }
+    for register in [KeyboardRegister::Lower, KeyboardRegister::Upper] {
+        if let Some(material) = material_assets.get_mut(&materials.ivory(register)) {
+            material.base_color = program_tint(active_program.program_for(register));
+        }
+    }
+}
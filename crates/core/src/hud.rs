@@ -0,0 +1,589 @@
+use crate::{
+    ActiveProgram, Dynamics, KeyboardRegister, Settings,
+    arpeggiator::{ArpMode, Arpeggiator},
+    assets::ProgramAssets,
+    chords::ChordMode,
+    effects::{Effects, FilterKind},
+    looper::{Looper, LooperState},
+    metronome::Metronome,
+    midi_file::MidiSong,
+    mixer::{Mixer, Pan},
+    pedal::Pedals,
+    player::MidiPlayer,
+    practice::{Practice, PracticeHand},
+    recorder::Recorder,
+    scale::{ScaleLock, ScaleLockMode},
+    utils::{note_name, pitch_class_name},
+};
+use bevy::{asset::LoadState, prelude::*};
+use std::fmt::Write;
+
+const HUD_FONT_SIZE: f32 = 14.0;
+const HUD_MARGIN: f32 = 8.0;
+const BUTTON_COLOR: Color = Color::srgb(0.25, 0.25, 0.25);
+const BUTTON_ON_COLOR: Color = Color::srgb(0.2, 0.45, 0.7);
+/// The keys that control features rather than play notes
+const KEY_HELP: &str = "\
+Tab: next program, Shift+Tab: previous
+Numpad1/3: lower octave, Numpad4/6: lower transpose
+Numpad7/9: upper octave, Numpad-/+: upper transpose
+Numpad0: scale lock, Shift+Numpad0: scale, Numpad.: root up, Shift+Numpad.: down
+NumpadEnter: play song, Numpad5: rewind, Numpad/ and *: seek, Numpad2/8: tempo
+PageUp/PageDown: dynamics, with Ctrl/Shift/Alt: lower/upper/master gain
+Space: sustain, Enter: sostenuto, Backspace: all notes off
+Arrows: bend and modulation, Ctrl+Up/Down: bend range, Alt: effect settings
+F4: program effects, F5-F9: filter, chorus, delay, reverb, compressor
+F10: next keymap preset, F11: timing-sensitive dynamics, F12: forte/piano layer
+CapsLock: metronome, 1: tap tempo
+Home/End/Delete: loop record, stop, undo
+Insert: record, Shift+Insert: render take
+ScrollLock/Pause: lower/upper arpeggiator
+Backslash: chords, Backquote: practice
+Escape: quit";
+
+pub struct HudPlugin;
+
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, (create_hud, create_mixer_panel))
+            .add_systems(
+                Update,
+                (
+                    toggle_key_help,
+                    update_status_text,
+                    update_program_list_text,
+                    (press_mixer_buttons, update_mixer_panel).chain(),
+                ),
+            );
+    }
+}
+
+#[derive(Component)]
+struct StatusText;
+
+#[derive(Component)]
+struct ProgramListText;
+
+/// Shows and hides the list of feature keys
+#[derive(Component)]
+struct KeyHelpButton;
+
+#[derive(Component)]
+struct KeyHelpText;
+
+/// The level of a register, or the master volume without one
+#[derive(Component)]
+struct MixerLevelText(Option<KeyboardRegister>);
+
+#[derive(Component, Clone, Copy, Debug)]
+enum MixerButton {
+    Gain(Option<KeyboardRegister>, f32),
+    LayerGain(KeyboardRegister, f32),
+    Pan(KeyboardRegister),
+    Mute(KeyboardRegister),
+    Solo(KeyboardRegister),
+}
+
+impl MixerButton {
+    fn press(self, mixer: &mut Mixer) {
+        match self {
+            MixerButton::Gain(register, steps) => mixer.step_gain(register, steps),
+            MixerButton::LayerGain(register, steps) => mixer.step_layer_gain(register, steps),
+            MixerButton::Pan(register) => {
+                let strip = mixer.strip_mut(register);
+                strip.pan = strip.pan.next();
+            }
+            MixerButton::Mute(register) => {
+                let strip = mixer.strip_mut(register);
+                strip.mute = !strip.mute;
+            }
+            MixerButton::Solo(register) => {
+                let strip = mixer.strip_mut(register);
+                strip.solo = !strip.solo;
+            }
+        }
+    }
+
+    /// The button's label, and whether it shows a setting that is on
+    fn label(self, mixer: &Mixer) -> (String, bool) {
+        match self {
+            MixerButton::Gain(_, steps) if steps > 0.0 => ("+".to_owned(), false),
+            MixerButton::Gain(..) => ("-".to_owned(), false),
+            MixerButton::LayerGain(_, steps) if steps > 0.0 => ("Layers +".to_owned(), false),
+            MixerButton::LayerGain(..) => ("Layers -".to_owned(), false),
+            MixerButton::Pan(register) => {
+                let pan = mixer.strip(register).pan;
+                let side = match pan {
+                    Pan::Left => "L",
+                    Pan::Centre => "C",
+                    Pan::Right => "R",
+                };
+                (format!("Pan {}", side), pan != Pan::Centre)
+            }
+            MixerButton::Mute(register) => ("Mute".to_owned(), mixer.strip(register).mute),
+            MixerButton::Solo(register) => ("Solo".to_owned(), mixer.strip(register).solo),
+        }
+    }
+}
+
+fn create_hud(mut commands: Commands) {
+    commands.spawn((
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(HUD_MARGIN),
+            left: Val::Px(HUD_MARGIN),
+            flex_direction: FlexDirection::Column,
+            row_gap: Val::Px(HUD_MARGIN),
+            padding: UiRect::all(Val::Px(HUD_MARGIN)),
+            ..default()
+        },
+        BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+        children![
+            (
+                StatusText,
+                Text::default(),
+                TextFont::from_font_size(HUD_FONT_SIZE),
+                TextColor(Color::WHITE),
+            ),
+            (
+                ProgramListText,
+                Text::default(),
+                TextFont::from_font_size(HUD_FONT_SIZE),
+                TextColor(Color::srgb(0.8, 0.8, 0.8)),
+            ),
+            (
+                KeyHelpButton,
+                Button,
+                Node {
+                    align_self: AlignSelf::FlexStart,
+                    padding: UiRect::axes(Val::Px(6.0), Val::Px(2.0)),
+                    ..default()
+                },
+                BackgroundColor(BUTTON_COLOR),
+                Text::new("Keys"),
+                TextFont::from_font_size(HUD_FONT_SIZE),
+                TextColor(Color::WHITE),
+            ),
+            (
+                KeyHelpText,
+                Text::new(KEY_HELP),
+                Node {
+                    display: Display::None,
+                    ..default()
+                },
+                TextFont::from_font_size(HUD_FONT_SIZE),
+                TextColor(Color::srgb(0.8, 0.8, 0.8)),
+            ),
+        ],
+    ));
+}
+
+fn toggle_key_help(
+    button: Single<
+        (&Interaction, &mut BackgroundColor),
+        (Changed<Interaction>, With<KeyHelpButton>),
+    >,
+    mut help: Single<&mut Node, With<KeyHelpText>>,
+) {
+    let (interaction, mut color) = button.into_inner();
+    if *interaction != Interaction::Pressed {
+        return;
+    }
+    let shown = help.display == Display::None;
+    help.display = match shown {
+        true => Display::Flex,
+        false => Display::None,
+    };
+    color.0 = match shown {
+        true => BUTTON_ON_COLOR,
+        false => BUTTON_COLOR,
+    };
+}
+
+fn create_mixer_panel(mut commands: Commands) {
+    let font = TextFont::from_font_size(HUD_FONT_SIZE);
+    commands
+        .spawn((
+            Node {
+                position_type: PositionType::Absolute,
+                top: Val::Px(HUD_MARGIN),
+                right: Val::Px(HUD_MARGIN),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(HUD_MARGIN / 2.0),
+                padding: UiRect::all(Val::Px(HUD_MARGIN)),
+                ..default()
+            },
+            BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+        ))
+        .with_children(|panel| {
+            for register in [
+                Some(KeyboardRegister::Upper),
+                Some(KeyboardRegister::Lower),
+                None,
+            ] {
+                let mut buttons = vec![
+                    MixerButton::Gain(register, -1.0),
+                    MixerButton::Gain(register, 1.0),
+                ];
+                if let Some(register) = register {
+                    buttons.extend([
+                        MixerButton::Pan(register),
+                        MixerButton::Mute(register),
+                        MixerButton::Solo(register),
+                        MixerButton::LayerGain(register, -1.0),
+                        MixerButton::LayerGain(register, 1.0),
+                    ]);
+                }
+                panel
+                    .spawn(Node {
+                        align_items: AlignItems::Center,
+                        column_gap: Val::Px(HUD_MARGIN / 2.0),
+                        ..default()
+                    })
+                    .with_children(|row| {
+                        row.spawn((
+                            MixerLevelText(register),
+                            Text::default(),
+                            Node {
+                                width: Val::Px(HUD_FONT_SIZE * 8.0),
+                                ..default()
+                            },
+                            font.clone(),
+                            TextColor(Color::WHITE),
+                        ));
+                        for button in buttons {
+                            row.spawn((
+                                Button,
+                                button,
+                                Node {
+                                    padding: UiRect::axes(Val::Px(6.0), Val::Px(2.0)),
+                                    ..default()
+                                },
+                                BackgroundColor(BUTTON_COLOR),
+                                Text::default(),
+                                font.clone(),
+                                TextColor(Color::WHITE),
+                            ));
+                        }
+                    });
+            }
+        });
+}
+
+fn press_mixer_buttons(
+    buttons: Query<(&Interaction, &MixerButton), Changed<Interaction>>,
+    mut mixer: ResMut<Mixer>,
+) {
+    for (interaction, button) in buttons.iter() {
+        if *interaction == Interaction::Pressed {
+            button.press(&mut mixer);
+            println!("DID SET MIXER: {:?}", button);
+        }
+    }
+}
+
+fn update_mixer_panel(
+    mixer: Res<Mixer>,
+    mut levels: Query<(&MixerLevelText, &mut Text), Without<MixerButton>>,
+    mut buttons: Query<(&MixerButton, &mut Text, &mut BackgroundColor)>,
+) {
+    if !mixer.is_changed() {
+        return;
+    }
+    for (level, mut text) in levels.iter_mut() {
+        text.0 = match level.0 {
+            Some(register) if !mixer.is_audible(register) => format!("{:?} silent", register),
+            Some(register) => format!("{:?} {:.0}%", register, mixer.strip(register).gain * 100.0),
+            None => format!("Master {:.0}%", mixer.master * 100.0),
+        };
+    }
+    for (button, mut text, mut color) in buttons.iter_mut() {
+        let (label, on) = button.label(&mixer);
+        text.0 = label;
+        color.0 = match on {
+            true => BUTTON_ON_COLOR,
+            false => BUTTON_COLOR,
+        };
+    }
+}
+
+fn update_status_text(
+    settings: Res<Settings>,
+    dynamics: Res<Dynamics>,
+    pedals: Res<Pedals>,
+    active_program: Res<ActiveProgram>,
+    programs: Res<ProgramAssets>,
+    recorder: Res<Recorder>,
+    player: Res<MidiPlayer>,
+    songs: Res<Assets<MidiSong>>,
+    practice: Res<Practice>,
+    metronome: Res<Metronome>,
+    looper: Res<Looper>,
+    arpeggiator: Res<Arpeggiator>,
+    chord_mode: Res<ChordMode>,
+    scale_lock: Res<ScaleLock>,
+    effects: Res<Effects>,
+    mixer: Res<Mixer>,
+    mut text: Single<&mut Text, With<StatusText>>,
+) {
+    if !settings.is_changed()
+        && !dynamics.is_changed()
+        && !pedals.is_changed()
+        && !active_program.is_changed()
+        && !programs.is_changed()
+        && !recorder.is_changed()
+        && !player.is_changed()
+        && !practice.is_changed()
+        && !metronome.is_changed()
+        && !looper.is_changed()
+        && !arpeggiator.is_changed()
+        && !chord_mode.is_changed()
+        && !scale_lock.is_changed()
+        && !effects.is_changed()
+        && !mixer.is_changed()
+    {
+        return;
+    }
+    let mut status = String::new();
+    let program_name = |program_no: usize| {
+        programs
+            .get(program_no)
+            .map_or("", |program| program.name.as_str())
+    };
+    let program = match active_program.program_no {
+        Some(program_no) => format!("Program {}: {}", program_no, program_name(program_no)),
+        None => "No program".to_owned(),
+    };
+    writeln!(status, "{}", program).ok();
+    for (label, register) in [
+        ("Upper", KeyboardRegister::Upper),
+        ("Lower", KeyboardRegister::Lower),
+    ] {
+        let (note_on_first_key, transpose) = match register {
+            KeyboardRegister::Lower => (settings.note_on_z, settings.transpose_lower),
+            KeyboardRegister::Upper => (settings.note_on_q, settings.transpose_upper),
+        };
+        write!(
+            status,
+            "{}: from {}, transpose {:+}",
+            label,
+            note_name(note_on_first_key),
+            transpose
+        )
+        .ok();
+        match active_program.program_for(register) {
+            Some(program_no) if Some(program_no) != active_program.program_no => {
+                write!(
+                    status,
+                    ", program {}: {}",
+                    program_no,
+                    program_name(program_no)
+                )
+                .ok();
+            }
+            _ => {}
+        }
+        for layer in active_program
+            .layers
+            .iter()
+            .filter(|layer| layer.register == register)
+        {
+            write!(
+                status,
+                " + {}: {} at {:.0}%",
+                layer.program_no,
+                program_name(layer.program_no),
+                mixer.strip(register).layer_gain * 100.0
+            )
+            .ok();
+        }
+        writeln!(status).ok();
+    }
+    write!(
+        status,
+        "Dynamics {:.0}% {:?}, timing {}  Sustain {}  Sostenuto {}  Bend range {}",
+        dynamics.level * 100.0,
+        dynamics.layer,
+        on_off(dynamics.timing_sensitive),
+        on_off(pedals.sustain),
+        on_off(pedals.sostenuto),
+        settings.bend_range
+    )
+    .ok();
+    if let Some(song) = player.song(&songs) {
+        write!(
+            status,
+            "\nSong: {} {} / {} {} x{:.2}",
+            player.song_name,
+            minutes_and_seconds(player.position_secs),
+            minutes_and_seconds(song.duration_secs()),
+            match player.playing {
+                true => "playing",
+                false => "paused",
+            },
+            player.tempo_scale
+        )
+        .ok();
+    }
+    if metronome.enabled {
+        write!(
+            status,
+            "\nMetronome {:.0} BPM {}/{}, accent {}",
+            metronome.bpm,
+            metronome.beats_per_bar,
+            metronome.beat_unit,
+            on_off(metronome.accent)
+        )
+        .ok();
+    }
+    if effects.is_active() {
+        let settings = &effects.settings;
+        let mut chain = vec![];
+        if settings.filter.kind != FilterKind::Off {
+            chain.push(format!(
+                "{:?} {:.0}Hz",
+                settings.filter.kind, settings.filter.cutoff_hz
+            ));
+        }
+        for (name, enabled) in [
+            ("chorus", settings.chorus.enabled),
+            ("delay", settings.delay.enabled),
+            ("reverb", settings.reverb.enabled),
+            ("compressor", settings.compressor.enabled),
+        ] {
+            if enabled {
+                chain.push(name.to_owned());
+            }
+        }
+        write!(
+            status,
+            "\nEffects: {}  [{:?} {:.3}]",
+            chain.join(", "),
+            effects.selected,
+            effects.selected.value(settings)
+        )
+        .ok();
+    }
+    if scale_lock.mode != ScaleLockMode::Off {
+        write!(
+            status,
+            "\nScale lock {:?}: {} {:?}",
+            scale_lock.mode,
+            pitch_class_name(scale_lock.root),
+            scale_lock.scale
+        )
+        .ok();
+    }
+    if chord_mode.enabled {
+        write!(
+            status,
+            "\nChords: {:?} {:?}",
+            chord_mode.kind,
+            chord_mode.intervals()
+        )
+        .ok();
+    }
+    for (label, register) in [
+        ("upper", KeyboardRegister::Upper),
+        ("lower", KeyboardRegister::Lower),
+    ] {
+        let arp = arpeggiator.settings(register);
+        if arp.mode == ArpMode::Off {
+            continue;
+        }
+        write!(
+            status,
+            "\nArpeggiator {}: {:?} {:?}, {} per beat, {} octaves, {:.0}% gate",
+            label,
+            arp.mode,
+            arp.pattern,
+            arp.rate,
+            arp.octaves,
+            arp.gate * 100.0
+        )
+        .ok();
+    }
+    if looper.state != LooperState::Empty {
+        write!(
+            status,
+            "\nLooper {:?}: {} layers, {} bars ({:.1}s)",
+            looper.state,
+            looper.layer_count(),
+            looper.bars,
+            looper.length_secs()
+        )
+        .ok();
+    }
+    if practice.hand != PracticeHand::Off {
+        let (chord, chords) = practice.progress();
+        write!(
+            status,
+            "\nPractice {:?}: chord {}/{}, {:.0}% accuracy",
+            practice.hand,
+            chord,
+            chords,
+            practice.stats.accuracy() * 100.0
+        )
+        .ok();
+    }
+    if let Some(summary) = practice.summary {
+        write!(
+            status,
+            "\nLast practice: {} chords, {:.0}% accuracy, {:.2}s average delay",
+            summary.chords,
+            summary.accuracy() * 100.0,
+            summary.mean_delay_secs()
+        )
+        .ok();
+    }
+    if recorder.recording {
+        write!(status, "\nRecording").ok();
+    }
+    text.0 = status;
+}
+
+fn update_program_list_text(
+    programs: Res<ProgramAssets>,
+    active_program: Res<ActiveProgram>,
+    mut text: Single<&mut Text, With<ProgramListText>>,
+) {
+    if !programs.is_changed() && !active_program.is_changed() {
+        return;
+    }
+    let mut list = String::new();
+    for program in programs.programs.iter() {
+        let marker = match active_program.is_playing(program.program_no) {
+            true => ">",
+            false => " ",
+        };
+        let key = program
+            .key
+            .map_or(String::new(), |key| format!(" [{:?}]", key));
+        let state = match (&program.error, &program.load_state) {
+            (Some(error), _) => format!("failed: {}", error),
+            (None, LoadState::NotLoaded) => "not loaded".to_owned(),
+            (None, LoadState::Loading) => "loading".to_owned(),
+            (None, LoadState::Loaded) => "ready".to_owned(),
+            (None, LoadState::Failed(error)) => format!("failed: {}", error),
+        };
+        writeln!(
+            list,
+            "{} {:>2}{} {} ({}) - {}",
+            marker, program.program_no, key, program.name, program.category, state
+        )
+        .ok();
+    }
+    text.0 = list.trim_end().to_owned();
+}
+
+fn on_off(on: bool) -> &'static str {
+    match on {
+        true => "on",
+        false => "off",
+    }
+}
+
+fn minutes_and_seconds(secs: f64) -> String {
+    let secs = secs.max(0.0) as u64;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
@@ -1,130 +1,223 @@
 use crate::{
-    KeyEvent, KeyboardRegister, Settings, StartProgramEvent,
-    utils::{make_note, on_lower},
+    ActiveProgram, AllNotesOffEvent, Dynamics, KeyEvent, KeyInputEvent, KeySource,
+    KeyboardRegister, Pedal, PedalEvent, ProgramTarget, RegisterShift, Settings, StartProgramEvent,
+    VelocityLayer, assets::ProgramAssets, keymap::KeyBindings, pipeline::KeyPipelineSet,
 };
-use bevy::prelude::*;
+use bevy::{platform::collections::HashMap, prelude::*};
 use bevy_midi_graph::midi::event::{Event, EventTarget, Message};
 
 const NODE_ID_LOWER: u64 = 0;
 const NODE_ID_UPPER: u64 = 1;
+const DYNAMICS_STEP: f32 = 0.1;
+pub(crate) const DYNAMICS_MIN: f32 = 0.1;
+const TIMING_FAST_SECS: f32 = 0.08;
+const TIMING_SLOW_SECS: f32 = 0.6;
+const TIMING_MAX_SCALE: f32 = 1.25;
+const TIMING_MIN_SCALE: f32 = 0.75;
+const TIMING_KEY: KeyCode = KeyCode::F11;
+const LAYER_KEY: KeyCode = KeyCode::F12;
 
 pub struct InputPlugin;
 
 impl Plugin for InputPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(PreUpdate, post_input_events);
+        app.add_systems(
+            PreUpdate,
+            (post_input_events, post_program_events).in_set(KeyPipelineSet::Input),
+        );
     }
 }
 
 fn post_input_events(
     inputs: Res<ButtonInput<KeyCode>>,
-    settings: Res<Settings>,
-    mut note_events: EventWriter<KeyEvent>,
-    mut program_events: EventWriter<StartProgramEvent>,
+    key_bindings: Res<KeyBindings>,
+    programs: Res<ProgramAssets>,
+    mut settings: ResMut<Settings>,
+    time: Res<Time>,
+    mut dynamics: ResMut<Dynamics>,
+    mut note_events: EventWriter<KeyInputEvent>,
+    mut pedal_events: EventWriter<PedalEvent>,
+    mut all_notes_off: EventWriter<AllNotesOffEvent>,
     mut quit_signal: EventWriter<AppExit>,
+    mut last_note_secs: Local<Option<f32>>,
+    mut held_notes: Local<HashMap<KeyCode, (KeyboardRegister, u8)>>,
 ) {
+    // With modifiers held, PageUp and PageDown control the mixer instead
+    let modified = inputs.any_pressed([
+        KeyCode::ControlLeft,
+        KeyCode::ControlRight,
+        KeyCode::ShiftLeft,
+        KeyCode::ShiftRight,
+        KeyCode::AltLeft,
+        KeyCode::AltRight,
+    ]);
+    if inputs.just_pressed(KeyCode::PageUp) && !modified {
+        dynamics.level = (dynamics.level + DYNAMICS_STEP).min(1.0);
+    }
+    if inputs.just_pressed(KeyCode::PageDown) && !modified {
+        dynamics.level = (dynamics.level - DYNAMICS_STEP).max(DYNAMICS_MIN);
+    }
+    // Unless the manifest gives the key to a program
+    if inputs.just_pressed(TIMING_KEY) && programs.program_for_key(&TIMING_KEY).is_none() {
+        dynamics.timing_sensitive = !dynamics.timing_sensitive;
+    }
+    if inputs.just_pressed(LAYER_KEY) && programs.program_for_key(&LAYER_KEY).is_none() {
+        dynamics.layer = dynamics.layer.next();
+    }
+    let vel = note_velocity(&dynamics, *last_note_secs, time.elapsed_secs());
     for key in inputs.get_just_pressed() {
-        if let Some((register, note)) = note_from_key_code(key, &settings) {
-            let node_id = match register {
-                KeyboardRegister::Lower => NODE_ID_LOWER,
-                KeyboardRegister::Upper => NODE_ID_UPPER,
-            };
-            note_events.write(KeyEvent {
+        if let Some((register, note)) = key_bindings.note_for(key, &settings) {
+            note_events.write(KeyInputEvent(make_key_event(
                 register,
-                message: Message {
-                    target: EventTarget::SpecificNode(node_id),
-                    data: Event::NoteOn { note, vel: 1.0 },
-                },
-            });
+                Event::NoteOn { note, vel },
+            )));
+            held_notes.insert(*key, (register, note));
+            *last_note_secs = Some(time.elapsed_secs());
         }
-        if let Some(program_no) = program_no_from_key_code(key) {
-            program_events.write(StartProgramEvent { program_no });
+        if let Some(pedal) = pedal_from_key_code(key) {
+            pedal_events.write(PedalEvent { pedal, down: true });
         }
     }
     for key in inputs.get_just_released() {
-        if let Some((register, note)) = note_from_key_code(key, &settings) {
-            let node_id = match register {
-                KeyboardRegister::Lower => NODE_ID_LOWER,
-                KeyboardRegister::Upper => NODE_ID_UPPER,
-            };
-            note_events.write(KeyEvent {
+        if let Some((register, note)) = held_notes.remove(key) {
+            note_events.write(KeyInputEvent(make_key_event(
                 register,
-                message: Message {
-                    target: EventTarget::SpecificNode(node_id),
-                    data: Event::NoteOff { note, vel: 1.0 },
-                },
-            });
+                Event::NoteOff { note, vel: 1.0 },
+            )));
+        }
+        if let Some(pedal) = pedal_from_key_code(key) {
+            pedal_events.write(PedalEvent { pedal, down: false });
         }
     }
+    for key in inputs.get_just_pressed() {
+        let Some((register, shift)) = register_shift_from_key_code(key) else {
+            continue;
+        };
+        if !settings.can_shift_register(register, shift) {
+            continue;
+        }
+        held_notes.retain(|_, (held_register, note)| {
+            if *held_register != register {
+                return true;
+            }
+            note_events.write(KeyInputEvent(make_key_event(
+                register,
+                Event::NoteOff {
+                    note: *note,
+                    vel: 1.0,
+                },
+            )));
+            false
+        });
+        settings.shift_register(register, shift);
+    }
+    if inputs.just_pressed(KeyCode::Backspace) {
+        all_notes_off.write(AllNotesOffEvent);
+    }
     if inputs.just_pressed(KeyCode::Escape) {
         quit_signal.write(AppExit::Success);
     }
 }
 
-fn note_from_key_code(key: &KeyCode, settings: &Settings) -> Option<(KeyboardRegister, u8)> {
-    let note_on_z = settings.note_on_z;
+/// Tab steps through the programs. A program's own key plays it on the
+/// whole keyboard, on the lower register with Ctrl or on the upper register
+/// with Shift. With Alt it is layered on the upper register instead, or on
+/// the lower register with Ctrl+Alt.
+fn post_program_events(
+    inputs: Res<ButtonInput<KeyCode>>,
+    programs: Res<ProgramAssets>,
+    active_program: Res<ActiveProgram>,
+    mut program_events: EventWriter<StartProgramEvent>,
+) {
+    let shift = inputs.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    let ctrl = inputs.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    let alt = inputs.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]);
+    for key in inputs.get_just_pressed() {
+        let (program_no, target) = match key {
+            KeyCode::Tab => {
+                let step = match shift {
+                    true => -1,
+                    false => 1,
+                };
+                let program_no = programs.program_after(active_program.program_no, step);
+                (program_no, ProgramTarget::Keyboard)
+            }
+            _ => {
+                let target = match (alt, ctrl, shift) {
+                    (true, true, _) => ProgramTarget::Layer(KeyboardRegister::Lower),
+                    (true, false, _) => ProgramTarget::Layer(KeyboardRegister::Upper),
+                    (false, true, _) => ProgramTarget::Register(KeyboardRegister::Lower),
+                    (false, false, true) => ProgramTarget::Register(KeyboardRegister::Upper),
+                    (false, false, false) => ProgramTarget::Keyboard,
+                };
+                (programs.program_for_key(key), target)
+            }
+        };
+        if let Some(program_no) = program_no {
+            program_events.write(StartProgramEvent { program_no, target });
+        }
+    }
+}
+
+/// Velocity for notes pressed this frame, from the selected velocity layer.
+/// With timing sensitivity on, quick successive presses play louder.
+fn note_velocity(dynamics: &Dynamics, last_note_secs: Option<f32>, now_secs: f32) -> f32 {
+    let base = match dynamics.layer {
+        VelocityLayer::Level => dynamics.level,
+        VelocityLayer::Forte => dynamics.forte,
+        VelocityLayer::Piano => dynamics.piano,
+    };
+    let scale = match (dynamics.timing_sensitive, last_note_secs) {
+        (true, Some(last_secs)) => {
+            let t = ((now_secs - last_secs - TIMING_FAST_SECS)
+                / (TIMING_SLOW_SECS - TIMING_FAST_SECS))
+                .clamp(0.0, 1.0);
+            TIMING_MAX_SCALE + t * (TIMING_MIN_SCALE - TIMING_MAX_SCALE)
+        }
+        _ => 1.0,
+    };
+    (base * scale).clamp(DYNAMICS_MIN, 1.0)
+}
+
+/// The node of the active program that plays a register
+pub(crate) fn node_id_for(register: KeyboardRegister) -> u64 {
+    match register {
+        KeyboardRegister::Lower => NODE_ID_LOWER,
+        KeyboardRegister::Upper => NODE_ID_UPPER,
+    }
+}
+
+pub(crate) fn make_key_event(register: KeyboardRegister, data: Event) -> KeyEvent {
+    KeyEvent {
+        register,
+        source: KeySource::Live,
+        message: Message {
+            target: EventTarget::SpecificNode(node_id_for(register)),
+            data,
+        },
+        due_secs: None,
+    }
+}
+
+fn register_shift_from_key_code(key: &KeyCode) -> Option<(KeyboardRegister, RegisterShift)> {
+    use KeyboardRegister::{Lower, Upper};
     match key {
-        KeyCode::KeyZ => on_lower(true, make_note(note_on_z, 0, false)),
-        KeyCode::KeyS => on_lower(true, make_note(note_on_z, 0, true)),
-        KeyCode::KeyX => on_lower(true, make_note(note_on_z, 1, false)),
-        KeyCode::KeyD => on_lower(true, make_note(note_on_z, 1, true)),
-        KeyCode::KeyC => on_lower(true, make_note(note_on_z, 2, false)),
-        KeyCode::KeyF => on_lower(true, make_note(note_on_z, 2, true)),
-        KeyCode::KeyV => on_lower(true, make_note(note_on_z, 3, false)),
-        KeyCode::KeyG => on_lower(true, make_note(note_on_z, 3, true)),
-        KeyCode::KeyB => on_lower(true, make_note(note_on_z, 4, false)),
-        KeyCode::KeyH => on_lower(true, make_note(note_on_z, 4, true)),
-        KeyCode::KeyN => on_lower(true, make_note(note_on_z, 5, false)),
-        KeyCode::KeyJ => on_lower(true, make_note(note_on_z, 5, true)),
-        KeyCode::KeyM => on_lower(true, make_note(note_on_z, 6, false)),
-        KeyCode::KeyK => on_lower(true, make_note(note_on_z, 6, true)),
-        KeyCode::Comma => on_lower(true, make_note(note_on_z, 7, false)),
-        KeyCode::KeyL => on_lower(true, make_note(note_on_z, 7, true)),
-        KeyCode::Period => on_lower(true, make_note(note_on_z, 8, false)),
-        KeyCode::Semicolon => on_lower(true, make_note(note_on_z, 8, true)),
-        KeyCode::Slash => on_lower(true, make_note(note_on_z, 9, false)),
-        KeyCode::Quote => on_lower(true, make_note(note_on_z, 9, true)),
-        KeyCode::KeyQ => on_lower(false, make_note(note_on_z, 7, false)),
-        KeyCode::Digit2 => on_lower(false, make_note(note_on_z, 7, true)),
-        KeyCode::KeyW => on_lower(false, make_note(note_on_z, 8, false)),
-        KeyCode::Digit3 => on_lower(false, make_note(note_on_z, 8, true)),
-        KeyCode::KeyE => on_lower(false, make_note(note_on_z, 9, false)),
-        KeyCode::Digit4 => on_lower(false, make_note(note_on_z, 9, true)),
-        KeyCode::KeyR => on_lower(false, make_note(note_on_z, 10, false)),
-        KeyCode::Digit5 => on_lower(false, make_note(note_on_z, 10, true)),
-        KeyCode::KeyT => on_lower(false, make_note(note_on_z, 11, false)),
-        KeyCode::Digit6 => on_lower(false, make_note(note_on_z, 11, true)),
-        KeyCode::KeyY => on_lower(false, make_note(note_on_z, 12, false)),
-        KeyCode::Digit7 => on_lower(false, make_note(note_on_z, 12, true)),
-        KeyCode::KeyU => on_lower(false, make_note(note_on_z, 13, false)),
-        KeyCode::Digit8 => on_lower(false, make_note(note_on_z, 13, true)),
-        KeyCode::KeyI => on_lower(false, make_note(note_on_z, 14, false)),
-        KeyCode::Digit9 => on_lower(false, make_note(note_on_z, 14, true)),
-        KeyCode::KeyO => on_lower(false, make_note(note_on_z, 15, false)),
-        KeyCode::Digit0 => on_lower(false, make_note(note_on_z, 15, true)),
-        KeyCode::KeyP => on_lower(false, make_note(note_on_z, 16, false)),
-        KeyCode::Minus => on_lower(false, make_note(note_on_z, 16, true)),
-        KeyCode::BracketLeft => on_lower(false, make_note(note_on_z, 16, false)),
-        KeyCode::Equal => on_lower(false, make_note(note_on_z, 16, true)),
-        KeyCode::BracketRight => on_lower(false, make_note(note_on_z, 16, false)),
+        KeyCode::Numpad1 => Some((Lower, RegisterShift::Octave(-1))),
+        KeyCode::Numpad3 => Some((Lower, RegisterShift::Octave(1))),
+        KeyCode::Numpad4 => Some((Lower, RegisterShift::Transpose(-1))),
+        KeyCode::Numpad6 => Some((Lower, RegisterShift::Transpose(1))),
+        KeyCode::Numpad7 => Some((Upper, RegisterShift::Octave(-1))),
+        KeyCode::Numpad9 => Some((Upper, RegisterShift::Octave(1))),
+        KeyCode::NumpadSubtract => Some((Upper, RegisterShift::Transpose(-1))),
+        KeyCode::NumpadAdd => Some((Upper, RegisterShift::Transpose(1))),
         _ => None,
     }
 }
 
-fn program_no_from_key_code(key: &KeyCode) -> Option<usize> {
+fn pedal_from_key_code(key: &KeyCode) -> Option<Pedal> {
     match key {
-        KeyCode::F1 => Some(1),
-        KeyCode::F2 => Some(2),
-        KeyCode::F3 => Some(3),
-        KeyCode::F4 => Some(4),
-        KeyCode::F5 => Some(5),
-        KeyCode::F6 => Some(6),
-        KeyCode::F7 => Some(7),
-        KeyCode::F8 => Some(8),
-        KeyCode::F9 => Some(9),
-        KeyCode::F10 => Some(10),
-        KeyCode::F11 => Some(11),
-        KeyCode::F12 => Some(12),
+        KeyCode::Space => Some(Pedal::Sustain),
+        KeyCode::Enter => Some(Pedal::Sostenuto),
         _ => None,
     }
 }
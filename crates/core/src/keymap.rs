@@ -0,0 +1,222 @@
+use crate::{KeyboardRegister, Settings, assets::ProgramAssets, utils::JsonAssetLoader};
+use bevy::{
+    asset::AssetLoadFailedEvent, platform::collections::HashMap, prelude::*, reflect::TypePath,
+};
+use serde::Deserialize;
+
+const PRESET_KEY: KeyCode = KeyCode::F10;
+const PRESETS: [&str; 4] = [
+    "keymaps/qwerty.keymap.json",
+    "keymaps/azerty.keymap.json",
+    "keymaps/qwertz.keymap.json",
+    "keymaps/dvorak.keymap.json",
+];
+
+pub struct KeymapPlugin;
+
+impl Plugin for KeymapPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<Keymap>()
+            .register_asset_loader(JsonAssetLoader::<Keymap>::new(&["keymap.json"]))
+            .init_resource::<ActiveKeymap>()
+            .init_resource::<KeyBindings>()
+            .add_systems(
+                PreUpdate,
+                (cycle_keymap_preset, load_keymap, apply_keymap).chain(),
+            );
+    }
+}
+
+/// A keyboard layout mapping physical keys to notes.
+/// Key codes name the physical key position (as on a US QWERTY keyboard), so
+/// the layout presets differ only in the optional labels printed on each key,
+/// which are shown on the keys they play. F10 steps through the presets
+/// unless the manifest gives it to a program.
+#[derive(Asset, TypePath, Deserialize)]
+pub struct Keymap {
+    pub name: String,
+    pub bindings: Vec<KeyBinding>,
+}
+
+#[derive(Deserialize)]
+pub struct KeyBinding {
+    pub key: KeyCode,
+    #[serde(default)]
+    pub label: Option<String>,
+    pub register: KeyboardRegister,
+    #[serde(flatten)]
+    pub target: KeyTarget,
+}
+
+/// What a key plays: either a position on the register's visual keyboard,
+/// which follows octave and transpose changes, or a fixed note.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum KeyTarget {
+    Layout {
+        white_key: usize,
+        #[serde(default)]
+        black: bool,
+    },
+    Note {
+        note: u8,
+    },
+}
+
+impl Keymap {
+    /// Describe any bindings that reuse a key or play the same note as
+    /// another binding, layout positions being played at the given settings.
+    pub fn validate(&self, settings: &Settings) -> Vec<String> {
+        let mut problems = vec![];
+        let mut keys: HashMap<KeyCode, usize> = HashMap::default();
+        let mut notes: HashMap<(KeyboardRegister, u8), usize> = HashMap::default();
+        for (index, binding) in self.bindings.iter().enumerate() {
+            if let Some(previous) = keys.insert(binding.key, index) {
+                problems.push(format!(
+                    "Binding {} reuses key {:?} from binding {}",
+                    index, binding.key, previous
+                ));
+            }
+            if let KeyTarget::Note { note } = binding.target {
+                if note > 127 {
+                    problems.push(format!("Binding {} has invalid note {}", index, note));
+                }
+            }
+            let note = match binding.target {
+                KeyTarget::Layout { white_key, black } => {
+                    settings.note_for(binding.register, white_key, black)
+                }
+                KeyTarget::Note { note } => Some(note).filter(|note| *note < 128),
+            };
+            let Some(note) = note else {
+                continue;
+            };
+            if let Some(previous) = notes.insert((binding.register, note), index) {
+                problems.push(format!(
+                    "Binding {} ({:?}) plays the same {:?} note {} as binding {} ({:?})",
+                    index,
+                    binding.key,
+                    binding.register,
+                    note,
+                    previous,
+                    self.bindings[previous].key
+                ));
+            }
+        }
+        problems
+    }
+}
+
+#[derive(Resource, Default)]
+struct ActiveKeymap {
+    path: String,
+    handle: Handle<Keymap>,
+}
+
+/// Lookup of the active keymap's bindings and the labels of the positions
+/// they play, rebuilt whenever it (re)loads.
+#[derive(Resource, Default)]
+pub struct KeyBindings {
+    keys: HashMap<KeyCode, (KeyboardRegister, KeyTarget)>,
+    labels: HashMap<(KeyboardRegister, usize, bool), String>,
+}
+
+impl KeyBindings {
+    pub fn note_for(&self, key: &KeyCode, settings: &Settings) -> Option<(KeyboardRegister, u8)> {
+        let (register, target) = self.keys.get(key)?;
+        let note = match *target {
+            KeyTarget::Layout { white_key, black } => {
+                settings.note_for(*register, white_key, black)
+            }
+            KeyTarget::Note { note } => Some(note).filter(|note| *note < 128),
+        }?;
+        Some((*register, note))
+    }
+
+    /// The label of the key playing a position on a register's keyboard
+    pub fn label_for(
+        &self,
+        register: KeyboardRegister,
+        white_key: usize,
+        black: bool,
+    ) -> Option<&str> {
+        self.labels
+            .get(&(register, white_key, black))
+            .map(String::as_str)
+    }
+}
+
+/// Step through the keymap presets, starting from the first if a keymap of
+/// its own is in use
+fn cycle_keymap_preset(
+    inputs: Res<ButtonInput<KeyCode>>,
+    programs: Res<ProgramAssets>,
+    mut settings: ResMut<Settings>,
+) {
+    if !inputs.just_pressed(PRESET_KEY) || programs.program_for_key(&PRESET_KEY).is_some() {
+        return;
+    }
+    let index = PRESETS
+        .iter()
+        .position(|path| *path == settings.keymap)
+        .map_or(0, |index| (index + 1) % PRESETS.len());
+    settings.keymap = PRESETS[index].to_owned();
+    println!("DID SET KEYMAP: {}", settings.keymap);
+}
+
+fn load_keymap(
+    server: Res<AssetServer>,
+    settings: Res<Settings>,
+    mut active_keymap: ResMut<ActiveKeymap>,
+) {
+    if active_keymap.path == settings.keymap {
+        return;
+    }
+    active_keymap.path = settings.keymap.clone();
+    active_keymap.handle = server.load(&settings.keymap);
+}
+
+fn apply_keymap(
+    mut events: EventReader<AssetEvent<Keymap>>,
+    mut failed_events: EventReader<AssetLoadFailedEvent<Keymap>>,
+    keymap_assets: Res<Assets<Keymap>>,
+    active_keymap: Res<ActiveKeymap>,
+    settings: Res<Settings>,
+    mut key_bindings: ResMut<KeyBindings>,
+) {
+    let active_id = active_keymap.handle.id();
+    for event in events.read() {
+        let id = match event {
+            AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id } => *id,
+            _ => continue,
+        };
+        if id != active_id {
+            continue;
+        }
+        let Some(keymap) = keymap_assets.get(id) else {
+            continue;
+        };
+        for problem in keymap.validate(&settings) {
+            warn!("Keymap {}: {}", keymap.name, problem);
+        }
+        key_bindings.keys = keymap
+            .bindings
+            .iter()
+            .map(|binding| (binding.key, (binding.register, binding.target)))
+            .collect();
+        key_bindings.labels = keymap
+            .bindings
+            .iter()
+            .filter_map(|binding| match (binding.target, &binding.label) {
+                (KeyTarget::Layout { white_key, black }, Some(label)) => {
+                    Some(((binding.register, white_key, black), label.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        println!("DID APPLY KEYMAP: {}", keymap.name);
+    }
+    for event in failed_events.read() {
+        warn!("Failed loading keymap {}: {}", event.path, event.error);
+    }
+}
@@ -1,34 +1,162 @@
 use bevy::prelude::*;
 use bevy_midi_graph::{MidiGraphPlugin, midi::event::Message};
+use serde::{Deserialize, Serialize};
 
+mod active_notes;
+mod arpeggiator;
 mod assets;
+mod bus;
+mod chords;
+mod effects;
+mod ensemble;
 mod graphics;
+mod hud;
 mod input;
+mod keymap;
+mod looper;
+mod metronome;
+mod midi_file;
+mod midi_input;
+mod mixer;
 mod output;
+mod pedal;
+mod piano_roll;
+mod pipeline;
+mod player;
+mod practice;
+mod recorder;
+mod render;
+mod save;
+mod scale;
+mod settings_store;
+mod touch;
 mod utils;
+mod wheels;
+
+use input::DYNAMICS_MIN;
+use utils::make_note;
+use wheels::BEND_RANGE_MAX;
+
+pub use arpeggiator::{ArpMode, ArpPattern, ArpSettings, Arpeggiator};
+pub use assets::{ProgramAsset, ProgramAssets, ProgramEntry, ProgramLoadError, ProgramManifest};
+pub use chords::{ChordKind, ChordMode};
+pub use effects::{
+    ChorusSettings, CompressorSettings, DelaySettings, EffectParameter, Effects, EffectsSettings,
+    FilterKind, FilterSettings, ReverbSettings,
+};
+pub use keymap::{KeyBinding, KeyTarget, Keymap};
+pub use looper::{Looper, LooperState};
+pub use metronome::Metronome;
+pub use midi_file::{ChannelRouting, MidiSong, SongEvent, TimedEvent, read_events};
+pub use midi_input::{MidiInputQueue, MidiInputSettings};
+pub use mixer::{ChannelStrip, Mixer, Pan};
+pub use player::MidiPlayerSettings;
+pub use recorder::RecorderSettings;
+pub use render::{
+    HeadlessRenderPlugin, OfflineRenderEvent, OfflineRenderFinishedEvent, WavSampleFormat,
+};
+pub use scale::{Scale, ScaleLock, ScaleLockMode};
+pub use settings_store::SettingsStore;
+pub use wheels::Wheels;
 
 #[derive(Event, Deref, DerefMut, Debug)]
 pub struct StartProgramEvent {
+    #[deref]
     pub program_no: usize,
+    pub target: ProgramTarget,
 }
 
-#[derive(Event, Deref, DerefMut, Debug)]
+/// What a started program plays
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgramTarget {
+    /// Both registers, clearing any split or layers
+    Keyboard,
+    /// One register, splitting the keyboard
+    Register(KeyboardRegister),
+    /// One register on top of its own program, or no longer if it already was
+    Layer(KeyboardRegister),
+}
+
+#[derive(Event, Deref, DerefMut, Debug, Clone)]
 pub struct KeyEvent {
     pub register: KeyboardRegister,
+    pub source: KeySource,
     #[deref]
     pub message: Message,
+    /// When the event is to sound on the output bus's clock, for events
+    /// scheduled ahead. Others sound at once.
+    pub due_secs: Option<f64>,
+}
+
+/// Whether a key was played live, stepped by the arpeggiator from keys
+/// played live, played by the looper or played from a song
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeySource {
+    #[default]
+    Live,
+    Arp,
+    Loop,
+    Song,
+}
+
+/// A program that could not be loaded, or combined with others, and so
+/// cannot be played
+#[derive(Event, Debug)]
+pub struct ProgramLoadFailedEvent {
+    pub program_no: usize,
+    pub error: ProgramLoadError,
+}
+
+/// A key played on an input device, before it passes through the key pipeline
+#[derive(Event, Deref, DerefMut, Debug)]
+pub struct KeyInputEvent(pub KeyEvent);
+
+/// Release every sounding note and return the keyboard to rest
+#[derive(Event, Debug)]
+pub struct AllNotesOffEvent;
+
+#[derive(Event, Debug)]
+pub struct PedalEvent {
+    pub pedal: Pedal,
+    pub down: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pedal {
+    Sustain,
+    Sostenuto,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
 pub enum KeyboardRegister {
     Lower,
     Upper,
 }
 
-#[derive(Resource)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegisterShift {
+    Octave(i8),
+    Transpose(i8),
+}
+
+const LOWEST_NOTE_ON_KEY: u8 = 12;
+const HIGHEST_NOTE_ON_KEY: u8 = 84;
+const TRANSPOSE_LIMIT: i8 = 12;
+
+/// Keyboard layout settings.
+/// `note_on_z` and `note_on_q` are the white notes on the first key of each
+/// register, and the transpose amounts are added to every note played.
+/// `keymap` is the asset path of the keymap binding keys to the registers,
+/// and `bend_range` the semitones the pitch bend wheel reaches.
+#[derive(Resource, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Settings {
     pub note_on_z: u8,
     pub note_on_q: u8,
+    pub transpose_lower: i8,
+    pub transpose_upper: i8,
+    pub keymap: String,
+    pub bend_range: u8,
 }
 
 impl Default for Settings {
@@ -36,6 +164,237 @@ impl Default for Settings {
         Self {
             note_on_z: 36,
             note_on_q: 48,
+            transpose_lower: 0,
+            transpose_upper: 0,
+            keymap: "keymaps/qwerty.keymap.json".to_owned(),
+            bend_range: 2,
+        }
+    }
+}
+
+impl Settings {
+    pub fn note_for(
+        &self,
+        register: KeyboardRegister,
+        white_key_advance: usize,
+        black_key: bool,
+    ) -> Option<u8> {
+        let (note_on_first_key, transpose) = match register {
+            KeyboardRegister::Lower => (self.note_on_z, self.transpose_lower),
+            KeyboardRegister::Upper => (self.note_on_q, self.transpose_upper),
+        };
+        make_note(note_on_first_key, white_key_advance, black_key)
+            .and_then(|note| note.checked_add_signed(transpose))
+            .filter(|note| *note < 128)
+    }
+
+    pub fn can_shift_register(&self, register: KeyboardRegister, shift: RegisterShift) -> bool {
+        let (note_on_first_key, transpose) = match register {
+            KeyboardRegister::Lower => (self.note_on_z, self.transpose_lower),
+            KeyboardRegister::Upper => (self.note_on_q, self.transpose_upper),
+        };
+        match shift {
+            RegisterShift::Octave(octaves) => {
+                is_first_key_note(note_on_first_key as i16 + 12 * octaves as i16)
+            }
+            RegisterShift::Transpose(semitones) => {
+                is_transpose(transpose as i16 + semitones as i16)
+            }
+        }
+    }
+
+    /// Return any value the controls could not have set to its default,
+    /// describing each one, so that stored settings cannot break the keyboard
+    pub(crate) fn reset_invalid(&mut self) -> Vec<String> {
+        let defaults = Settings::default();
+        let mut problems = vec![];
+        for (name, note, default) in [
+            ("note_on_z", &mut self.note_on_z, defaults.note_on_z),
+            ("note_on_q", &mut self.note_on_q, defaults.note_on_q),
+        ] {
+            if !is_first_key_note(*note as i16) {
+                problems.push(format!("{} {} is not a white note in range", name, note));
+                *note = default;
+            }
+        }
+        for (name, transpose, default) in [
+            (
+                "transpose_lower",
+                &mut self.transpose_lower,
+                defaults.transpose_lower,
+            ),
+            (
+                "transpose_upper",
+                &mut self.transpose_upper,
+                defaults.transpose_upper,
+            ),
+        ] {
+            if !is_transpose(*transpose as i16) {
+                problems.push(format!("{} {} is out of range", name, transpose));
+                *transpose = default;
+            }
+        }
+        if !(1..=BEND_RANGE_MAX).contains(&self.bend_range) {
+            problems.push(format!("bend_range {} is out of range", self.bend_range));
+            self.bend_range = defaults.bend_range;
+        }
+        problems
+    }
+
+    pub fn shift_register(&mut self, register: KeyboardRegister, shift: RegisterShift) {
+        let (note_on_first_key, transpose) = match register {
+            KeyboardRegister::Lower => (&mut self.note_on_z, &mut self.transpose_lower),
+            KeyboardRegister::Upper => (&mut self.note_on_q, &mut self.transpose_upper),
+        };
+        match shift {
+            RegisterShift::Octave(octaves) => {
+                *note_on_first_key = (*note_on_first_key as i16 + 12 * octaves as i16) as u8
+            }
+            RegisterShift::Transpose(semitones) => *transpose += semitones,
+        }
+    }
+}
+
+/// Whether the first key of a register can play a note, which must be white
+/// for the keys after it to follow the keyboard
+fn is_first_key_note(note: i16) -> bool {
+    (LOWEST_NOTE_ON_KEY as i16..=HIGHEST_NOTE_ON_KEY as i16).contains(&note)
+        && matches!(note % 12, 0 | 2 | 4 | 5 | 7 | 9 | 11)
+}
+
+fn is_transpose(semitones: i16) -> bool {
+    (-TRANSPOSE_LIMIT as i16..=TRANSPOSE_LIMIT as i16).contains(&semitones)
+}
+
+/// Velocity model for computer-keyboard playing.
+/// `level` is the dynamics slider; `forte` and `piano` are the layers F12
+/// switches to in turn, as `layer`, before returning to the slider. With
+/// `timing_sensitive` on, notes played quickly after the last are louder and
+/// slow ones softer; F11 toggles it.
+#[derive(Resource, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Dynamics {
+    pub level: f32,
+    pub forte: f32,
+    pub piano: f32,
+    pub layer: VelocityLayer,
+    pub timing_sensitive: bool,
+}
+
+/// Which of the dynamics keyboard notes are played at
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VelocityLayer {
+    #[default]
+    Level,
+    Forte,
+    Piano,
+}
+
+impl VelocityLayer {
+    pub fn next(self) -> Self {
+        match self {
+            VelocityLayer::Level => VelocityLayer::Forte,
+            VelocityLayer::Forte => VelocityLayer::Piano,
+            VelocityLayer::Piano => VelocityLayer::Level,
+        }
+    }
+}
+
+impl Default for Dynamics {
+    fn default() -> Self {
+        Self {
+            level: 0.7,
+            forte: 1.0,
+            piano: 0.35,
+            layer: VelocityLayer::Level,
+            timing_sensitive: false,
+        }
+    }
+}
+
+impl Dynamics {
+    /// Return any level out of range to its default, describing each one
+    pub(crate) fn reset_invalid(&mut self) -> Vec<String> {
+        let defaults = Dynamics::default();
+        let mut problems = vec![];
+        for (name, level, default) in [
+            ("level", &mut self.level, defaults.level),
+            ("forte", &mut self.forte, defaults.forte),
+            ("piano", &mut self.piano, defaults.piano),
+        ] {
+            if !(DYNAMICS_MIN..=1.0).contains(&*level) {
+                problems.push(format!("dynamics {} {} is out of range", name, level));
+                *level = default;
+            }
+        }
+        problems
+    }
+}
+
+/// The programs played by the keyboard.
+/// `program_no` plays both registers unless `lower` or `upper` split the
+/// keyboard, and `layers` play on a register alongside its own program.
+#[derive(Resource, Default)]
+pub struct ActiveProgram {
+    pub program_no: Option<usize>,
+    pub lower: Option<usize>,
+    pub upper: Option<usize>,
+    pub layers: Vec<ProgramLayer>,
+}
+
+/// A program played on a register alongside its own program, at the
+/// register's layer gain in the mixer
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgramLayer {
+    pub register: KeyboardRegister,
+    pub program_no: usize,
+}
+
+impl ActiveProgram {
+    /// The program a register plays, before any layers
+    pub fn program_for(&self, register: KeyboardRegister) -> Option<usize> {
+        match register {
+            KeyboardRegister::Lower => self.lower.or(self.program_no),
+            KeyboardRegister::Upper => self.upper.or(self.program_no),
+        }
+    }
+
+    /// Whether a program plays anywhere on the keyboard
+    pub fn is_playing(&self, program_no: usize) -> bool {
+        self.program_no == Some(program_no)
+            || self.lower == Some(program_no)
+            || self.upper == Some(program_no)
+            || self
+                .layers
+                .iter()
+                .any(|layer| layer.program_no == program_no)
+    }
+
+    pub fn start(&mut self, program_no: usize, target: ProgramTarget) {
+        match target {
+            ProgramTarget::Keyboard => {
+                *self = Self {
+                    program_no: Some(program_no),
+                    ..default()
+                };
+            }
+            ProgramTarget::Register(KeyboardRegister::Lower) => self.lower = Some(program_no),
+            ProgramTarget::Register(KeyboardRegister::Upper) => self.upper = Some(program_no),
+            ProgramTarget::Layer(register) => {
+                let existing = self
+                    .layers
+                    .iter()
+                    .position(|layer| layer.register == register && layer.program_no == program_no);
+                match existing {
+                    Some(index) => {
+                        self.layers.remove(index);
+                    }
+                    None => self.layers.push(ProgramLayer {
+                        register,
+                        program_no,
+                    }),
+                }
+            }
         }
     }
 }
@@ -45,13 +404,45 @@ pub struct ShiningPianoPlugin;
 impl Plugin for ShiningPianoPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<StartProgramEvent>()
+            .add_event::<ProgramLoadFailedEvent>()
             .add_event::<KeyEvent>()
+            .add_event::<KeyInputEvent>()
+            .add_event::<PedalEvent>()
+            .add_event::<AllNotesOffEvent>()
             .insert_resource(Settings::default())
+            .init_resource::<Dynamics>()
+            .init_resource::<ActiveProgram>()
             .add_plugins((
                 MidiGraphPlugin,
-                graphics::GraphicsPlugin,
-                input::InputPlugin,
-                output::OutputPlugin,
+                // Nested to stay within the size of plugin tuples
+                (
+                    active_notes::ActiveNotesPlugin,
+                    arpeggiator::ArpeggiatorPlugin,
+                    chords::ChordPlugin,
+                    effects::EffectsPlugin,
+                    graphics::GraphicsPlugin,
+                    hud::HudPlugin,
+                    input::InputPlugin,
+                    keymap::KeymapPlugin,
+                    looper::LooperPlugin,
+                    metronome::MetronomePlugin,
+                    midi_input::MidiInputPlugin,
+                    mixer::MixerPlugin,
+                ),
+                (
+                    output::OutputPlugin,
+                    pedal::PedalPlugin,
+                    piano_roll::PianoRollPlugin,
+                    pipeline::PipelinePlugin,
+                    player::MidiPlayerPlugin,
+                    practice::PracticePlugin,
+                    recorder::RecorderPlugin,
+                    render::OfflineRenderPlugin,
+                    scale::ScaleLockPlugin,
+                    settings_store::SettingsStorePlugin,
+                    touch::TouchPlugin,
+                    wheels::WheelsPlugin,
+                ),
                 assets::AssetsPlugin,
             ));
     }
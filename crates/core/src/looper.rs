@@ -0,0 +1,357 @@
+use crate::{
+    ActiveProgram, AllNotesOffEvent, KeyEvent, KeySource, KeyboardRegister,
+    bus::{Bus, SCHEDULE_AHEAD_SECS},
+    ensemble::{Ensemble, make_ensemble_key_event},
+    input::make_key_event,
+    metronome::Metronome,
+};
+use bevy::{platform::collections::HashSet, prelude::*};
+use bevy_midi_graph::midi::event::Event;
+use std::collections::BTreeSet;
+
+const RECORD_KEY: KeyCode = KeyCode::Home;
+const STOP_KEY: KeyCode = KeyCode::End;
+const UNDO_KEY: KeyCode = KeyCode::Delete;
+/// Notes held at the end of a layer are released this long before it loops
+const HELD_NOTE_RELEASE_SECS: f64 = 0.001;
+
+pub struct LooperPlugin;
+
+impl Plugin for LooperPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Looper>().add_systems(
+            Update,
+            (control_looper, record_loop, play_loop, update_ensemble).chain(),
+        );
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LooperState {
+    #[default]
+    Empty,
+    Recording,
+    Playing,
+    Overdubbing,
+    Stopped,
+}
+
+/// A note of a layer, at its time from the start of the loop
+struct LoopEvent {
+    offset_secs: f64,
+    register: KeyboardRegister,
+    data: Event,
+}
+
+/// One pass of playing over the loop, played back through the programs the
+/// registers played while it was recorded
+struct LoopLayer {
+    lower_program: Option<usize>,
+    upper_program: Option<usize>,
+    events: Vec<LoopEvent>,
+}
+
+impl LoopLayer {
+    fn program_for(&self, register: KeyboardRegister) -> Option<usize> {
+        match register {
+            KeyboardRegister::Lower => self.lower_program,
+            KeyboardRegister::Upper => self.upper_program,
+        }
+    }
+}
+
+/// Phrases recorded in layers and played over and over.
+/// Home records the first layer, `bars` bars long at the metronome tempo,
+/// then overdubs another layer for one pass of the playing loop. End stops
+/// the loop, or clears it when stopped, and Delete undoes the last layer.
+/// Loops are timed by the output bus's clock, and their notes are scheduled
+/// a little ahead so the bus plays each at its sample. Arpeggiated notes are
+/// recorded at the times they are due, as they are heard.
+#[derive(Resource)]
+pub struct Looper {
+    pub bars: u8,
+    pub state: LooperState,
+    layers: Vec<LoopLayer>,
+    recording: Option<LoopLayer>,
+    recording_held: HashSet<(KeyboardRegister, u8)>,
+    record_start_secs: f64,
+    length_secs: f64,
+    start_secs: f64,
+    played_to_secs: f64,
+    sounding: HashSet<(Option<usize>, KeyboardRegister, u8)>,
+}
+
+impl Default for Looper {
+    fn default() -> Self {
+        Self {
+            bars: 2,
+            state: LooperState::Empty,
+            layers: vec![],
+            recording: None,
+            recording_held: HashSet::default(),
+            record_start_secs: 0.0,
+            length_secs: 0.0,
+            start_secs: 0.0,
+            played_to_secs: 0.0,
+            sounding: HashSet::default(),
+        }
+    }
+}
+
+impl Looper {
+    pub fn layer_count(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn length_secs(&self) -> f64 {
+        self.length_secs
+    }
+
+    fn offset_secs(&self, secs: f64) -> f64 {
+        (secs - self.start_secs).rem_euclid(self.length_secs)
+    }
+
+    fn restart(&mut self, now_secs: f64) {
+        self.start_secs = now_secs;
+        self.played_to_secs = now_secs;
+    }
+
+    fn start_layer(&mut self, now_secs: f64, active_program: &ActiveProgram) {
+        self.recording = Some(LoopLayer {
+            lower_program: active_program.program_for(KeyboardRegister::Lower),
+            upper_program: active_program.program_for(KeyboardRegister::Upper),
+            events: vec![],
+        });
+        self.recording_held.clear();
+        self.record_start_secs = now_secs;
+    }
+
+    /// Keep the layer being recorded, releasing notes still held at its end
+    fn finish_layer(&mut self) {
+        let Some(mut layer) = self.recording.take() else {
+            return;
+        };
+        let end_offset_secs = self.offset_secs(self.record_start_secs - HELD_NOTE_RELEASE_SECS);
+        layer.events.extend(
+            self.recording_held
+                .drain()
+                .map(|(register, note)| LoopEvent {
+                    offset_secs: end_offset_secs,
+                    register,
+                    data: Event::NoteOff { note, vel: 1.0 },
+                }),
+        );
+        if layer.events.is_empty() {
+            return;
+        }
+        self.layers.push(layer);
+        println!("DID RECORD LOOP LAYER: {}", self.layers.len());
+    }
+
+    /// Release the notes sounding at the end of what has been scheduled, so
+    /// that notes scheduled but not yet heard are released too
+    fn release_notes(&mut self, key_events: &mut EventWriter<KeyEvent>) {
+        for (program_no, register, note) in self.sounding.drain() {
+            key_events.write(KeyEvent {
+                due_secs: Some(self.played_to_secs),
+                ..loop_key_event(program_no, register, Event::NoteOff { note, vel: 1.0 })
+            });
+        }
+    }
+
+    /// Programs the loop needs to sound alongside the active program
+    fn programs(&self) -> impl Iterator<Item = usize> + '_ {
+        self.layers
+            .iter()
+            .chain(self.recording.iter())
+            .flat_map(|layer| [layer.lower_program, layer.upper_program])
+            .flatten()
+    }
+}
+
+fn loop_key_event(program_no: Option<usize>, register: KeyboardRegister, data: Event) -> KeyEvent {
+    match program_no {
+        Some(program_no) => make_ensemble_key_event(program_no, register, KeySource::Loop, data),
+        None => KeyEvent {
+            source: KeySource::Loop,
+            ..make_key_event(register, data)
+        },
+    }
+}
+
+fn control_looper(
+    inputs: Res<ButtonInput<KeyCode>>,
+    metronome: Res<Metronome>,
+    bus: Res<Bus>,
+    active_program: Res<ActiveProgram>,
+    mut looper: ResMut<Looper>,
+    mut key_events: EventWriter<KeyEvent>,
+) {
+    let now_secs = bus.audio_secs();
+    if inputs.just_pressed(RECORD_KEY) {
+        match looper.state {
+            LooperState::Empty => {
+                looper.length_secs = looper.bars as f64 * metronome.bar_secs();
+                looper.restart(now_secs);
+                looper.start_layer(now_secs, &active_program);
+                looper.state = LooperState::Recording;
+            }
+            LooperState::Playing => {
+                looper.start_layer(now_secs, &active_program);
+                looper.state = LooperState::Overdubbing;
+            }
+            LooperState::Stopped => {
+                looper.restart(now_secs);
+                looper.state = LooperState::Playing;
+            }
+            LooperState::Recording | LooperState::Overdubbing => {}
+        }
+    }
+    if inputs.just_pressed(STOP_KEY) {
+        looper.recording = None;
+        looper.release_notes(&mut key_events);
+        looper.state = match looper.state {
+            LooperState::Recording | LooperState::Playing | LooperState::Overdubbing
+                if !looper.layers.is_empty() =>
+            {
+                LooperState::Stopped
+            }
+            _ => {
+                looper.layers.clear();
+                LooperState::Empty
+            }
+        };
+    }
+    if inputs.just_pressed(UNDO_KEY) {
+        if looper.recording.take().is_none() {
+            looper.layers.pop();
+            looper.release_notes(&mut key_events);
+        }
+        looper.state = match looper.state {
+            _ if looper.layers.is_empty() => LooperState::Empty,
+            LooperState::Overdubbing => LooperState::Playing,
+            state => state,
+        };
+    }
+}
+
+/// Record live notes into the layer being recorded, keeping the layer once
+/// it has recorded one pass of the loop
+fn record_loop(mut events: EventReader<KeyEvent>, bus: Res<Bus>, mut looper: ResMut<Looper>) {
+    if looper.recording.is_none() {
+        events.clear();
+        return;
+    }
+    let now_secs = bus.audio_secs();
+    let looper = looper.as_mut();
+    if let Some(layer) = looper.recording.as_mut() {
+        for event in events.read() {
+            if !matches!(event.source, KeySource::Live | KeySource::Arp) {
+                continue;
+            }
+            let offset_secs = (event.due_secs.unwrap_or(now_secs) - looper.start_secs)
+                .rem_euclid(looper.length_secs);
+            let recorded = match event.data {
+                Event::NoteOn { note, .. } => looper.recording_held.insert((event.register, note)),
+                Event::NoteOff { note, .. } => {
+                    looper.recording_held.remove(&(event.register, note))
+                }
+                _ => false,
+            };
+            if recorded {
+                layer.events.push(LoopEvent {
+                    offset_secs,
+                    register: event.register,
+                    data: event.data.clone(),
+                });
+            }
+        }
+    }
+    if now_secs - looper.record_start_secs >= looper.length_secs {
+        looper.finish_layer();
+        looper.state = match looper.layers.is_empty() {
+            true => LooperState::Empty,
+            false => LooperState::Playing,
+        };
+    }
+}
+
+/// Schedule the notes of every layer that fall due before the bus plays
+/// the next frame or so
+fn play_loop(
+    mut all_notes_off: EventReader<AllNotesOffEvent>,
+    bus: Res<Bus>,
+    mut looper: ResMut<Looper>,
+    mut key_events: EventWriter<KeyEvent>,
+) {
+    // Playback position is not shown, so it does not count as a change
+    let looper = looper.bypass_change_detection();
+    if !all_notes_off.is_empty() {
+        all_notes_off.clear();
+        // Notes scheduled ahead are cancelled by the output, so end at once
+        for (program_no, register, note) in looper.sounding.drain() {
+            key_events.write(loop_key_event(
+                program_no,
+                register,
+                Event::NoteOff { note, vel: 1.0 },
+            ));
+        }
+    }
+    if !matches!(
+        looper.state,
+        LooperState::Recording | LooperState::Playing | LooperState::Overdubbing
+    ) {
+        return;
+    }
+    let ahead_secs = bus.audio_secs() + SCHEDULE_AHEAD_SECS;
+    if ahead_secs <= looper.played_to_secs {
+        return;
+    }
+    let from_secs = looper.played_to_secs - looper.start_secs;
+    let to_secs = ahead_secs - looper.start_secs;
+    looper.played_to_secs = ahead_secs;
+
+    let length_secs = looper.length_secs;
+    let mut due = vec![];
+    for layer in looper.layers.iter() {
+        for event in layer.events.iter() {
+            let passes_before = ((from_secs - event.offset_secs) / length_secs).ceil();
+            let passes = ((to_secs - event.offset_secs) / length_secs).ceil();
+            if passes > passes_before {
+                let secs = event.offset_secs + passes_before * length_secs;
+                due.push((secs, layer.program_for(event.register), event));
+            }
+        }
+    }
+    due.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let start_secs = looper.start_secs;
+    for (secs, program_no, event) in due {
+        match event.data {
+            Event::NoteOn { note, .. } => {
+                looper.sounding.insert((program_no, event.register, note));
+            }
+            Event::NoteOff { note, .. } => {
+                looper.sounding.remove(&(program_no, event.register, note));
+            }
+            _ => {}
+        }
+        key_events.write(KeyEvent {
+            due_secs: Some(start_secs + secs),
+            ..loop_key_event(program_no, event.register, event.data.clone())
+        });
+    }
+}
+
+/// Sound the programs of the loop's layers while it plays or records
+fn update_ensemble(looper: Res<Looper>, mut ensemble: ResMut<Ensemble>) {
+    if !looper.is_changed() {
+        return;
+    }
+    let programs: BTreeSet<usize> = match looper.state {
+        LooperState::Empty | LooperState::Stopped => BTreeSet::new(),
+        _ => looper.programs().collect(),
+    };
+    if ensemble.looped != programs {
+        ensemble.looped = programs;
+    }
+}
@@ -0,0 +1,298 @@
+use crate::bus::Bus;
+use bevy::prelude::*;
+use bevy_midi_graph::midi::consts::{CHANNEL_COUNT, PLAYBACK_SAMPLE_RATE};
+use std::{
+    f32::consts::TAU,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, Ordering},
+};
+
+const TOGGLE_KEY: KeyCode = KeyCode::CapsLock;
+const TAP_KEY: KeyCode = KeyCode::Digit1;
+const CLICK_SECS: f32 = 0.03;
+const CLICK_FREQUENCY: f32 = 1000.0;
+const ACCENT_FREQUENCY: f32 = 1500.0;
+const CLICK_AMPLITUDE: f32 = 0.35;
+const ACCENT_AMPLITUDE: f32 = 0.6;
+const BPM_MIN: f32 = 30.0;
+const BPM_MAX: f32 = 300.0;
+const TAP_RESET_SECS: f32 = 2.0;
+const TAP_COUNT: usize = 5;
+const TIME_SIGNATURES: [(u8, u8); 5] = [(4, 4), (3, 4), (2, 4), (6, 8), (5, 4)];
+const INDICATOR_FLASH_SECS: f32 = 0.1;
+/// The beat in the bar of the last click once the clicks have stopped, so
+/// that they start again on a downbeat
+const NO_BEAT: u8 = u8::MAX;
+
+pub struct MetronomePlugin;
+
+impl Plugin for MetronomePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Metronome>()
+            .add_systems(Startup, create_beat_indicator)
+            .add_systems(
+                Update,
+                (control_metronome, share_metronome, show_beats).chain(),
+            );
+    }
+}
+
+/// Click track settings.
+/// Clicks are played by the output bus, on the beats of its frame clock,
+/// which is the clock the looper and arpeggiator schedule notes on. So the
+/// clicks keep time with them regardless of frame rate. Ctrl+CapsLock
+/// toggles the accent on the first beat of each bar and Shift+CapsLock
+/// cycles the time signature. `bpm` counts quarter notes a minute, and each
+/// beat lasts the note value of `beat_unit`, so 6/8 clicks eighth notes.
+#[derive(Resource)]
+pub struct Metronome {
+    pub enabled: bool,
+    pub bpm: f32,
+    pub beats_per_bar: u8,
+    pub beat_unit: u8,
+    pub accent: bool,
+}
+
+impl Default for Metronome {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bpm: 100.0,
+            beats_per_bar: 4,
+            beat_unit: 4,
+            accent: true,
+        }
+    }
+}
+
+impl Metronome {
+    pub(crate) fn beat_secs(&self) -> f64 {
+        beat_secs(self.bpm, self.beat_unit)
+    }
+
+    pub(crate) fn bar_secs(&self) -> f64 {
+        self.beats_per_bar as f64 * self.beat_secs()
+    }
+}
+
+/// The length of a beat of the given note value at a tempo in quarter notes
+fn beat_secs(bpm: f32, beat_unit: u8) -> f64 {
+    let quarter_secs = 60.0 / bpm.clamp(BPM_MIN, BPM_MAX) as f64;
+    quarter_secs * 4.0 / beat_unit.max(1) as f64
+}
+
+/// Settings shared with the audio thread, and the clicks it has played
+pub(crate) struct MetronomeClock {
+    enabled: AtomicBool,
+    accent: AtomicBool,
+    bpm_bits: AtomicU32,
+    beats_per_bar: AtomicU8,
+    beat_unit: AtomicU8,
+    beats: AtomicU64,
+    beat_in_bar: AtomicU8,
+    /// The beat of the bus's clock last reached while the clicks were on,
+    /// kept here so that a new program's bus carries on clicking
+    bus_beat: AtomicU64,
+}
+
+impl Default for MetronomeClock {
+    fn default() -> Self {
+        let metronome = Metronome::default();
+        let clock = Self {
+            enabled: AtomicBool::new(false),
+            accent: AtomicBool::new(false),
+            bpm_bits: AtomicU32::new(0),
+            beats_per_bar: AtomicU8::new(0),
+            beat_unit: AtomicU8::new(0),
+            beats: AtomicU64::new(0),
+            beat_in_bar: AtomicU8::new(NO_BEAT),
+            bus_beat: AtomicU64::new(u64::MAX),
+        };
+        clock.share(&metronome);
+        clock
+    }
+}
+
+impl MetronomeClock {
+    pub fn share(&self, metronome: &Metronome) {
+        self.enabled.store(metronome.enabled, Ordering::Relaxed);
+        self.accent.store(metronome.accent, Ordering::Relaxed);
+        self.bpm_bits
+            .store(metronome.bpm.to_bits(), Ordering::Relaxed);
+        self.beats_per_bar
+            .store(metronome.beats_per_bar, Ordering::Relaxed);
+        self.beat_unit.store(metronome.beat_unit, Ordering::Relaxed);
+    }
+}
+
+/// Clicks added to the output on the bus's beats, from the first beat after
+/// the metronome is turned on
+#[derive(Default)]
+pub(crate) struct Clicks {
+    /// The sample reached in the click sounding, and whether it is accented
+    click: Option<(u32, bool)>,
+}
+
+impl Clicks {
+    /// Add the clicks to a buffer starting at a frame of the bus's clock
+    pub fn fill(&mut self, clock: &MetronomeClock, buffer: &mut [f32], start_frame: u64) {
+        if !clock.enabled.load(Ordering::Relaxed) {
+            self.click = None;
+            clock.beat_in_bar.store(NO_BEAT, Ordering::Relaxed);
+            clock.bus_beat.store(u64::MAX, Ordering::Relaxed);
+            return;
+        }
+        let sample_rate = PLAYBACK_SAMPLE_RATE as f32;
+        let bpm = f32::from_bits(clock.bpm_bits.load(Ordering::Relaxed));
+        let beat_unit = clock.beat_unit.load(Ordering::Relaxed);
+        let beat_frames = beat_secs(bpm, beat_unit) * PLAYBACK_SAMPLE_RATE as f64;
+        let click_samples = (CLICK_SECS * sample_rate) as u32;
+        for (index, frame) in buffer.chunks_exact_mut(CHANNEL_COUNT).enumerate() {
+            let beat = ((start_frame + index as u64) as f64 / beat_frames) as u64;
+            let last = clock.bus_beat.swap(beat, Ordering::Relaxed);
+            if last != u64::MAX && last != beat {
+                self.start_click(clock);
+            }
+            let Some((index, accent)) = self.click else {
+                continue;
+            };
+            if index >= click_samples {
+                self.click = None;
+                continue;
+            }
+            self.click = Some((index + 1, accent));
+            let (frequency, amplitude) = match accent {
+                true => (ACCENT_FREQUENCY, ACCENT_AMPLITUDE),
+                false => (CLICK_FREQUENCY, CLICK_AMPLITUDE),
+            };
+            let envelope = (1.0 - index as f32 / click_samples as f32).powi(2);
+            let phase = TAU * frequency * index as f32 / sample_rate;
+            let sample = amplitude * envelope * phase.sin();
+            for channel in frame.iter_mut() {
+                *channel += sample;
+            }
+        }
+    }
+
+    fn start_click(&mut self, clock: &MetronomeClock) {
+        let beats_per_bar = clock.beats_per_bar.load(Ordering::Relaxed).max(1);
+        let beat_in_bar = match clock.beat_in_bar.load(Ordering::Relaxed) {
+            NO_BEAT => 0,
+            last => (last + 1) % beats_per_bar,
+        };
+        let accent = clock.accent.load(Ordering::Relaxed) && beat_in_bar == 0;
+        self.click = Some((0, accent));
+        clock.beat_in_bar.store(beat_in_bar, Ordering::Relaxed);
+        clock.beats.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn control_metronome(
+    inputs: Res<ButtonInput<KeyCode>>,
+    time: Res<Time>,
+    mut metronome: ResMut<Metronome>,
+    mut taps: Local<Vec<f32>>,
+) {
+    if inputs.just_pressed(TOGGLE_KEY) {
+        if inputs.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+            let current = (metronome.beats_per_bar, metronome.beat_unit);
+            let index = TIME_SIGNATURES
+                .iter()
+                .position(|signature| *signature == current)
+                .map_or(0, |index| (index + 1) % TIME_SIGNATURES.len());
+            (metronome.beats_per_bar, metronome.beat_unit) = TIME_SIGNATURES[index];
+        } else if inputs.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]) {
+            metronome.accent = !metronome.accent;
+        } else {
+            metronome.enabled = !metronome.enabled;
+        }
+    }
+    if inputs.just_pressed(TAP_KEY) {
+        let now = time.elapsed_secs();
+        if taps.last().is_some_and(|last| now - last > TAP_RESET_SECS) {
+            taps.clear();
+        }
+        taps.push(now);
+        if taps.len() > TAP_COUNT {
+            taps.remove(0);
+        }
+        if taps.len() > 1 {
+            // Taps mark beats, which are counted back into quarter notes
+            let interval = (taps[taps.len() - 1] - taps[0]) / (taps.len() - 1) as f32;
+            let quarter_interval = interval * metronome.beat_unit.max(1) as f32 / 4.0;
+            metronome.bpm = (60.0 / quarter_interval).clamp(BPM_MIN, BPM_MAX).round();
+        }
+    }
+}
+
+/// Hand changed settings to the bus, which plays the clicks
+fn share_metronome(metronome: Res<Metronome>, bus: Res<Bus>) {
+    if metronome.is_changed() {
+        bus.metronome().share(&metronome);
+    }
+}
+
+#[derive(Component)]
+struct BeatIndicator {
+    idle: Handle<StandardMaterial>,
+    beat: Handle<StandardMaterial>,
+    downbeat: Handle<StandardMaterial>,
+    flash: Timer,
+    beats_shown: u64,
+}
+
+fn create_beat_indicator(
+    mut commands: Commands,
+    mut material_assets: ResMut<Assets<StandardMaterial>>,
+    mut mesh_assets: ResMut<Assets<Mesh>>,
+) {
+    let idle = material_assets.add(StandardMaterial {
+        base_color: Color::srgb(0.25, 0.25, 0.25).into(),
+        ..default()
+    });
+    let beat = material_assets.add(StandardMaterial {
+        base_color: Color::srgb(0.3, 0.9, 0.4).into(),
+        emissive: LinearRgba::rgb(0.3, 0.9, 0.4),
+        ..default()
+    });
+    let downbeat = material_assets.add(StandardMaterial {
+        base_color: Color::srgb(0.95, 0.35, 0.2).into(),
+        emissive: LinearRgba::rgb(0.95, 0.35, 0.2),
+        ..default()
+    });
+    commands.spawn((
+        Mesh3d(mesh_assets.add(Sphere::new(0.08))),
+        MeshMaterial3d(idle.clone()),
+        Transform::from_xyz(-1.6, 0.1, -0.4),
+        BeatIndicator {
+            idle,
+            beat,
+            downbeat,
+            flash: Timer::from_seconds(INDICATOR_FLASH_SECS, TimerMode::Once),
+            beats_shown: 0,
+        },
+    ));
+}
+
+/// Light the indicator for each click the audio thread has played
+fn show_beats(
+    time: Res<Time>,
+    metronome: Res<Metronome>,
+    bus: Res<Bus>,
+    mut indicator_query: Query<(&mut BeatIndicator, &mut MeshMaterial3d<StandardMaterial>)>,
+) {
+    let clock = bus.metronome();
+    let beats = clock.beats.load(Ordering::Relaxed);
+    let beat_in_bar = clock.beat_in_bar.load(Ordering::Relaxed);
+    for (mut indicator, mut material) in indicator_query.iter_mut() {
+        if indicator.beats_shown != beats {
+            indicator.beats_shown = beats;
+            indicator.flash.reset();
+            material.0 = match metronome.accent && beat_in_bar == 0 {
+                true => indicator.downbeat.clone(),
+                false => indicator.beat.clone(),
+            };
+        } else if indicator.flash.tick(time.delta()).just_finished() {
+            material.0 = indicator.idle.clone();
+        }
+    }
+}
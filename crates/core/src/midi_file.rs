@@ -0,0 +1,164 @@
+use crate::KeyboardRegister;
+use bevy::{
+    asset::{AssetLoader, LoadContext, io::Reader},
+    prelude::*,
+    reflect::TypePath,
+};
+use bevy_midi_graph::midi::event::Event;
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+const DEFAULT_MICROS_PER_BEAT: u32 = 500_000;
+
+/// A Standard MIDI File read into the events it plays, in order.
+/// Loaded for `.mid` and `.midi` paths requested as this asset type.
+#[derive(Asset, TypePath)]
+pub struct MidiSong {
+    pub events: Vec<TimedEvent>,
+}
+
+impl MidiSong {
+    pub fn duration_secs(&self) -> f64 {
+        self.events.last().map_or(0.0, |event| event.secs)
+    }
+}
+
+/// An event from a MIDI file, placed in time and on its channel
+#[derive(Clone, Debug)]
+pub struct TimedEvent {
+    pub secs: f64,
+    pub channel: u8,
+    pub data: SongEvent,
+}
+
+/// A note, or a change to the numbered program of the manifest
+#[derive(Clone, Debug)]
+pub enum SongEvent {
+    Note(Event),
+    Program(usize),
+}
+
+/// The program a MIDI program change selects. MIDI counts programs from 0,
+/// and the manifest counts them from 1, as they are shown.
+pub(crate) fn program_no_for(program: u8) -> usize {
+    program as usize + 1
+}
+
+/// The MIDI program change that selects a program of the manifest
+pub(crate) fn midi_program_for(program_no: usize) -> u8 {
+    (program_no.clamp(1, 128) - 1) as u8
+}
+
+/// Which register each MIDI channel plays on.
+/// Channels in `lower_channels` play on the lower register and every other
+/// channel plays on the upper register. The default matches the files
+/// written by the recorder.
+#[derive(Clone, Debug)]
+pub struct ChannelRouting {
+    pub lower_channels: Vec<u8>,
+}
+
+impl Default for ChannelRouting {
+    fn default() -> Self {
+        Self {
+            lower_channels: vec![0],
+        }
+    }
+}
+
+impl ChannelRouting {
+    pub fn register_for(&self, channel: u8) -> KeyboardRegister {
+        match self.lower_channels.contains(&channel) {
+            true => KeyboardRegister::Lower,
+            false => KeyboardRegister::Upper,
+        }
+    }
+}
+
+/// Read the note and program change events of a Standard MIDI File in the
+/// order they play
+pub fn read_events(bytes: &[u8]) -> Result<Vec<TimedEvent>, String> {
+    let smf = Smf::parse(bytes).map_err(|error| error.to_string())?;
+    let mut track_events = vec![];
+    for track in smf.tracks.iter() {
+        let mut tick = 0u64;
+        for event in track.iter() {
+            tick += event.delta.as_int() as u64;
+            track_events.push((tick, event.kind));
+        }
+    }
+    track_events.sort_by_key(|(tick, _)| *tick);
+
+    let mut events = vec![];
+    let mut micros_per_beat = DEFAULT_MICROS_PER_BEAT;
+    let mut last_tick = 0;
+    let mut secs = 0.0;
+    for (tick, kind) in track_events {
+        let ticks = (tick - last_tick) as f64;
+        secs += match smf.header.timing {
+            Timing::Metrical(ticks_per_beat) => {
+                ticks * micros_per_beat as f64 / 1_000_000.0 / ticks_per_beat.as_int() as f64
+            }
+            Timing::Timecode(fps, ticks_per_frame) => {
+                ticks / (fps.as_f32() as f64 * ticks_per_frame as f64)
+            }
+        };
+        last_tick = tick;
+        let (channel, message) = match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                micros_per_beat = tempo.as_int();
+                continue;
+            }
+            TrackEventKind::Midi { channel, message } => (channel.as_int(), message),
+            _ => continue,
+        };
+        let data = match message {
+            MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                SongEvent::Note(Event::NoteOn {
+                    note: key.as_int(),
+                    vel: vel.as_int() as f32 / 127.0,
+                })
+            }
+            MidiMessage::NoteOn { key, vel } | MidiMessage::NoteOff { key, vel } => {
+                SongEvent::Note(Event::NoteOff {
+                    note: key.as_int(),
+                    vel: vel.as_int() as f32 / 127.0,
+                })
+            }
+            MidiMessage::ProgramChange { program } => {
+                SongEvent::Program(program_no_for(program.as_int()))
+            }
+            _ => continue,
+        };
+        events.push(TimedEvent {
+            secs,
+            channel,
+            data,
+        });
+    }
+    Ok(events)
+}
+
+#[derive(Default)]
+pub struct MidiSongLoader;
+
+impl AssetLoader for MidiSongLoader {
+    type Asset = MidiSong;
+    type Settings = ();
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).await?;
+        let events = read_events(&bytes)?;
+        Ok(MidiSong { events })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["mid", "midi"]
+    }
+}
@@ -0,0 +1,266 @@
+use crate::{
+    KeyInputEvent, KeyboardRegister, Pedal, PedalEvent, input::make_key_event,
+    pipeline::KeyPipelineSet,
+};
+use bevy::prelude::*;
+use bevy_midi_graph::midi::event::Event;
+use std::sync::{
+    Mutex,
+    mpsc::{Receiver, Sender, channel},
+};
+
+const STATUS_NOTE_OFF: u8 = 0x80;
+const STATUS_NOTE_ON: u8 = 0x90;
+const STATUS_CONTROL_CHANGE: u8 = 0xb0;
+const CONTROLLER_SUSTAIN: u8 = 64;
+const CONTROLLER_SOSTENUTO: u8 = 66;
+const CLIENT_NAME: &str = "shining-piano";
+
+pub struct MidiInputPlugin;
+
+impl Plugin for MidiInputPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MidiInputSettings>()
+            .init_resource::<MidiInputQueue>()
+            .add_systems(
+                PreUpdate,
+                post_midi_input_events.in_set(KeyPipelineSet::Input),
+            );
+        #[cfg(not(target_arch = "wasm32"))]
+        app.add_systems(Startup, hardware::open_midi_input);
+    }
+}
+
+/// Configuration for hardware MIDI input.
+/// Notes below `split_note` play on the lower register, and `split_note`
+/// and above on the upper register, whatever their channel. Every note
+/// plays, including those beyond the keys shown on screen, which do not
+/// light up, as an instrument may have more keys than the keyboard shows.
+#[derive(Resource)]
+pub struct MidiInputSettings {
+    pub port_name: Option<String>,
+    pub create_virtual_port: bool,
+    pub split_note: u8,
+}
+
+impl Default for MidiInputSettings {
+    fn default() -> Self {
+        Self {
+            port_name: None,
+            create_virtual_port: false,
+            split_note: 48,
+        }
+    }
+}
+
+/// Raw MIDI messages waiting to be turned into key events.
+/// Anything holding a sender from `MidiInputQueue::sender` acts as an input
+/// port, so a fake port only needs to send the bytes of each message.
+#[derive(Resource)]
+pub struct MidiInputQueue {
+    sender: Sender<Vec<u8>>,
+    receiver: Mutex<Receiver<Vec<u8>>>,
+}
+
+impl Default for MidiInputQueue {
+    fn default() -> Self {
+        let (sender, receiver) = channel();
+        Self {
+            sender,
+            receiver: Mutex::new(receiver),
+        }
+    }
+}
+
+impl MidiInputQueue {
+    pub fn sender(&self) -> Sender<Vec<u8>> {
+        self.sender.clone()
+    }
+}
+
+fn post_midi_input_events(
+    queue: Res<MidiInputQueue>,
+    settings: Res<MidiInputSettings>,
+    mut note_events: EventWriter<KeyInputEvent>,
+    mut pedal_events: EventWriter<PedalEvent>,
+) {
+    let Ok(receiver) = queue.receiver.lock() else {
+        return;
+    };
+    for bytes in receiver.try_iter() {
+        match message_from_midi_bytes(&bytes) {
+            Some(MidiInputMessage::Note(note, data)) => {
+                let register = match note < settings.split_note {
+                    true => KeyboardRegister::Lower,
+                    false => KeyboardRegister::Upper,
+                };
+                note_events.write(KeyInputEvent(make_key_event(register, data)));
+            }
+            Some(MidiInputMessage::Pedal(event)) => {
+                pedal_events.write(event);
+            }
+            None => {}
+        }
+    }
+}
+
+enum MidiInputMessage {
+    Note(u8, Event),
+    Pedal(PedalEvent),
+}
+
+fn message_from_midi_bytes(bytes: &[u8]) -> Option<MidiInputMessage> {
+    let [status, data_1, data_2, ..] = *bytes else {
+        return None;
+    };
+    let vel = data_2 as f32 / 127.0;
+    let note = data_1;
+    let pedal = |pedal| {
+        Some(MidiInputMessage::Pedal(PedalEvent {
+            pedal,
+            down: data_2 >= 64,
+        }))
+    };
+    match (status & 0xf0, data_1) {
+        (STATUS_NOTE_ON, _) if data_2 > 0 => {
+            Some(MidiInputMessage::Note(note, Event::NoteOn { note, vel }))
+        }
+        (STATUS_NOTE_ON | STATUS_NOTE_OFF, _) => {
+            Some(MidiInputMessage::Note(note, Event::NoteOff { note, vel }))
+        }
+        (STATUS_CONTROL_CHANGE, CONTROLLER_SUSTAIN) => pedal(Pedal::Sustain),
+        (STATUS_CONTROL_CHANGE, CONTROLLER_SOSTENUTO) => pedal(Pedal::Sostenuto),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod hardware {
+    use super::{CLIENT_NAME, MidiInputQueue, MidiInputSettings};
+    use bevy::prelude::*;
+    use midir::{Ignore, MidiInput, MidiInputConnection};
+
+    /// Keeps the port open for as long as the app runs.
+    struct MidiInputConnectionHandle(#[allow(dead_code)] MidiInputConnection<()>);
+
+    pub fn open_midi_input(world: &mut World) {
+        let settings = world.resource::<MidiInputSettings>();
+        let sender = world.resource::<MidiInputQueue>().sender();
+        let mut midi_input = match MidiInput::new(CLIENT_NAME) {
+            Ok(midi_input) => midi_input,
+            Err(error) => {
+                warn!("MIDI input unavailable: {}", error);
+                return;
+            }
+        };
+        midi_input.ignore(Ignore::All);
+        let callback = move |_: u64, bytes: &[u8], _: &mut ()| {
+            let _ = sender.send(bytes.to_vec());
+        };
+
+        #[cfg(unix)]
+        if settings.create_virtual_port {
+            use midir::os::unix::VirtualInput;
+            match midi_input.create_virtual(CLIENT_NAME, callback, ()) {
+                Ok(connection) => {
+                    println!("DID OPEN VIRTUAL MIDI PORT: {}", CLIENT_NAME);
+                    world.insert_non_send_resource(MidiInputConnectionHandle(connection));
+                }
+                Err(error) => warn!("Failed creating virtual MIDI port: {}", error),
+            }
+            return;
+        }
+
+        let ports = midi_input.ports();
+        let port = ports.iter().find(|port| match &settings.port_name {
+            Some(name) => midi_input
+                .port_name(port)
+                .is_ok_and(|port_name| port_name.contains(name.as_str())),
+            None => true,
+        });
+        let Some(port) = port else {
+            println!("NO MIDI INPUT PORT FOUND");
+            return;
+        };
+        let port_name = midi_input.port_name(port).unwrap_or_default();
+        match midi_input.connect(port, CLIENT_NAME, callback, ()) {
+            Ok(connection) => {
+                println!("DID OPEN MIDI PORT: {}", port_name);
+                world.insert_non_send_resource(MidiInputConnectionHandle(connection));
+            }
+            Err(error) => warn!("Failed connecting to MIDI port {}: {}", port_name, error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_messages_become_key_and_pedal_events() {
+        let mut app = App::new();
+        app.add_event::<KeyInputEvent>()
+            .add_event::<PedalEvent>()
+            .insert_resource(MidiInputSettings {
+                split_note: 60,
+                ..default()
+            })
+            .init_resource::<MidiInputQueue>()
+            .add_systems(Update, post_midi_input_events);
+        let sender = app.world().resource::<MidiInputQueue>().sender();
+        for bytes in [
+            [STATUS_NOTE_ON, 59, 127],
+            // The split note itself plays on the upper register
+            [STATUS_NOTE_ON, 60, 127],
+            // Other channels play too, split by note alone
+            [STATUS_NOTE_ON | 9, 48, 127],
+            [STATUS_NOTE_ON | 1, 61, 127],
+            // Notes beyond the keys shown still play
+            [STATUS_NOTE_ON | 15, 108, 127],
+            [STATUS_NOTE_OFF, 59, 0],
+            // A note on without velocity ends the note
+            [STATUS_NOTE_ON, 60, 0],
+            [STATUS_CONTROL_CHANGE, CONTROLLER_SUSTAIN, 127],
+            [STATUS_CONTROL_CHANGE, CONTROLLER_SUSTAIN, 0],
+            // Other controllers are ignored
+            [STATUS_CONTROL_CHANGE, 1, 90],
+        ] {
+            sender.send(bytes.to_vec()).unwrap();
+        }
+        app.update();
+
+        let key_events: Vec<_> = app
+            .world_mut()
+            .resource_mut::<Events<KeyInputEvent>>()
+            .drain()
+            .map(|event| match event.message.data {
+                Event::NoteOn { note, vel } => (event.register, note, Some(vel)),
+                Event::NoteOff { note, .. } => (event.register, note, None),
+                ref data => panic!("Unexpected key event {:?}", data),
+            })
+            .collect();
+        assert_eq!(
+            key_events,
+            vec![
+                (KeyboardRegister::Lower, 59, Some(1.0)),
+                (KeyboardRegister::Upper, 60, Some(1.0)),
+                (KeyboardRegister::Lower, 48, Some(1.0)),
+                (KeyboardRegister::Upper, 61, Some(1.0)),
+                (KeyboardRegister::Upper, 108, Some(1.0)),
+                (KeyboardRegister::Lower, 59, None),
+                (KeyboardRegister::Upper, 60, None),
+            ]
+        );
+        let pedal_events: Vec<_> = app
+            .world_mut()
+            .resource_mut::<Events<PedalEvent>>()
+            .drain()
+            .map(|event| (event.pedal, event.down))
+            .collect();
+        assert_eq!(
+            pedal_events,
+            vec![(Pedal::Sustain, true), (Pedal::Sustain, false)]
+        );
+    }
+}
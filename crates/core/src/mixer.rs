@@ -0,0 +1,200 @@
+use crate::KeyboardRegister;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+const GAIN_STEP: f32 = 0.1;
+const GAIN_MAX: f32 = 1.0;
+const LAYER_GAIN: f32 = 0.7;
+
+pub struct MixerPlugin;
+
+impl Plugin for MixerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Mixer>()
+            .add_systems(Update, control_mixer);
+    }
+}
+
+/// Which sides a register sounds on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Pan {
+    Left,
+    #[default]
+    Centre,
+    Right,
+}
+
+impl Pan {
+    pub fn next(self) -> Self {
+        match self {
+            Pan::Left => Pan::Centre,
+            Pan::Centre => Pan::Right,
+            Pan::Right => Pan::Left,
+        }
+    }
+
+    /// The gains of the left and right sides
+    pub fn gains(self) -> [f32; 2] {
+        match self {
+            Pan::Left => [1.0, 0.0],
+            Pan::Centre => [1.0, 1.0],
+            Pan::Right => [0.0, 1.0],
+        }
+    }
+}
+
+/// The level and placement of one register.
+/// `layer_gain` scales the velocity of programs layered on the register,
+/// relative to the register's own program.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelStrip {
+    pub gain: f32,
+    pub pan: Pan,
+    pub mute: bool,
+    pub solo: bool,
+    pub layer_gain: f32,
+}
+
+impl Default for ChannelStrip {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            pan: Pan::Centre,
+            mute: false,
+            solo: false,
+            layer_gain: LAYER_GAIN,
+        }
+    }
+}
+
+/// Levels of the registers and of everything played.
+/// Gains and pans apply to the output of the nodes each register plays, on
+/// the audio thread, so they reach notes that are already sounding. Soloing
+/// a register mutes the other unless it is soloed too. Held with Ctrl,
+/// PageUp and PageDown change the lower register's gain, with Shift the
+/// upper register's and with Alt the master volume. Layer gains are set
+/// from the mixer panel.
+#[derive(Resource, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Mixer {
+    pub master: f32,
+    pub lower: ChannelStrip,
+    pub upper: ChannelStrip,
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self {
+            master: 1.0,
+            lower: ChannelStrip::default(),
+            upper: ChannelStrip::default(),
+        }
+    }
+}
+
+impl Mixer {
+    pub fn strip(&self, register: KeyboardRegister) -> &ChannelStrip {
+        match register {
+            KeyboardRegister::Lower => &self.lower,
+            KeyboardRegister::Upper => &self.upper,
+        }
+    }
+
+    pub fn strip_mut(&mut self, register: KeyboardRegister) -> &mut ChannelStrip {
+        match register {
+            KeyboardRegister::Lower => &mut self.lower,
+            KeyboardRegister::Upper => &mut self.upper,
+        }
+    }
+
+    pub fn is_audible(&self, register: KeyboardRegister) -> bool {
+        let strip = self.strip(register);
+        let soloing = self.lower.solo || self.upper.solo;
+        !strip.mute && (strip.solo || !soloing)
+    }
+
+    /// What the output of a register is scaled by
+    pub fn level(&self, register: KeyboardRegister) -> f32 {
+        match self.is_audible(register) {
+            true => self.strip(register).gain * self.master,
+            false => 0.0,
+        }
+    }
+
+    /// The left and right gains of a register's output, or of the nodes
+    /// playing neither register without one
+    pub fn output_gains(&self, register: Option<KeyboardRegister>) -> [f32; 2] {
+        match register {
+            Some(register) => {
+                let level = self.level(register);
+                self.strip(register).pan.gains().map(|gain| gain * level)
+            }
+            None => [self.master; 2],
+        }
+    }
+
+    /// Return any gain out of range to its default, describing each one
+    pub(crate) fn reset_invalid(&mut self) -> Vec<String> {
+        let strip_defaults = ChannelStrip::default();
+        let mut problems = vec![];
+        let mut gains = vec![("master", &mut self.master, 1.0)];
+        for (register, strip) in [("lower", &mut self.lower), ("upper", &mut self.upper)] {
+            gains.push((register, &mut strip.gain, strip_defaults.gain));
+            gains.push((register, &mut strip.layer_gain, strip_defaults.layer_gain));
+        }
+        for (name, gain, default) in gains {
+            if !(0.0..=GAIN_MAX).contains(&*gain) {
+                problems.push(format!("mixer {} gain {} is out of range", name, gain));
+                *gain = default;
+            }
+        }
+        problems
+    }
+
+    /// Raise or lower a register's gain, or the master volume without one
+    pub fn step_gain(&mut self, register: Option<KeyboardRegister>, steps: f32) {
+        let gain = match register {
+            Some(register) => &mut self.strip_mut(register).gain,
+            None => &mut self.master,
+        };
+        step_gain(gain, steps);
+    }
+
+    /// Raise or lower the gain of the programs layered on a register
+    pub fn step_layer_gain(&mut self, register: KeyboardRegister, steps: f32) {
+        step_gain(&mut self.strip_mut(register).layer_gain, steps);
+    }
+}
+
+fn step_gain(gain: &mut f32, steps: f32) {
+    *gain = ((*gain + steps * GAIN_STEP) * 10.0).round() / 10.0;
+    *gain = gain.clamp(0.0, GAIN_MAX);
+}
+
+fn control_mixer(inputs: Res<ButtonInput<KeyCode>>, mut mixer: ResMut<Mixer>) {
+    let steps = match (
+        inputs.just_pressed(KeyCode::PageUp),
+        inputs.just_pressed(KeyCode::PageDown),
+    ) {
+        (true, false) => 1.0,
+        (false, true) => -1.0,
+        _ => return,
+    };
+    let register = if inputs.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]) {
+        Some(KeyboardRegister::Lower)
+    } else if inputs.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        Some(KeyboardRegister::Upper)
+    } else if inputs.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]) {
+        None
+    } else {
+        return;
+    };
+    mixer.step_gain(register, steps);
+    println!(
+        "DID SET MIXER: lower {:.0}%, upper {:.0}%, master {:.0}%",
+        mixer.lower.gain * 100.0,
+        mixer.upper.gain * 100.0,
+        mixer.master * 100.0
+    );
+}
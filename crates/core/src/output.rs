@@ -1,14 +1,27 @@
-use crate::{KeyEvent, StartProgramEvent};
+use crate::{
+    ActiveProgram, AllNotesOffEvent, KeyEvent, KeyboardRegister, ProgramTarget, StartProgramEvent,
+    active_notes::ActiveNotes,
+    assets::ProgramAssets,
+    bus::{Bus, share_mixer, share_modulation},
+    effects::{Effects, apply_program_effects},
+    ensemble::{Ensemble, compose_program, program_source, register_programs, route_key_event},
+    input::make_key_event,
+    mixer::Mixer,
+};
 use bevy::prelude::*;
 use bevy_midi_graph::{
-    GraphAssetLoader, MidiFileSource, MidiGraphAudioContext, Sf2FileSource, WaveFileSource,
+    GraphAssetLoader, MidiFileSource, MidiGraph, MidiGraphAudioContext, Sf2FileSource,
+    WaveFileSource,
     midi::{
-        event::Balance,
+        event::{Balance, Event},
         node::{NodeConfigData, SquareWave},
     },
 };
+use std::collections::BTreeSet;
 
 const PROGRAM_NO: usize = 0;
+/// Where the active program combined with the ensemble and the bus is stored
+const COMPOSITE_PROGRAM_NO: usize = usize::MAX;
 
 pub struct OutputPlugin;
 
@@ -16,23 +29,46 @@ impl Plugin for OutputPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(Startup, configure_audio)
             .add_systems(Update, play_key_events)
-            .add_systems(PostUpdate, change_program);
+            .init_resource::<Ensemble>()
+            .init_resource::<Bus>()
+            .add_systems(
+                PostUpdate,
+                (
+                    (
+                        release_notes_before_change,
+                        change_program,
+                        apply_program_effects,
+                        play_ensemble,
+                    )
+                        .chain(),
+                    share_mixer,
+                    share_modulation,
+                ),
+            );
     }
 }
 
 fn configure_audio(
+    bus: Res<Bus>,
+    effects: Res<Effects>,
     mut audio_context: ResMut<MidiGraphAudioContext>,
     server: Res<AssetServer>,
     midi_assets: Res<Assets<MidiFileSource>>,
     sf2_assets: Res<Assets<Sf2FileSource>>,
     wav_assets: Res<Assets<WaveFileSource>>,
 ) {
-    let config = NodeConfigData(Box::new(SquareWave {
-        node_id: None,
-        balance: Balance::Both,
-        amplitude: 0.125,
-        duty_cycle: 0.25,
-    }));
+    let config = bus.program(
+        vec![(
+            None,
+            NodeConfigData(Box::new(SquareWave {
+                node_id: None,
+                balance: Balance::Both,
+                amplitude: 0.125,
+                duty_cycle: 0.25,
+            })),
+        )],
+        &effects,
+    );
     let mut loader = GraphAssetLoader::new(&server, &midi_assets, &sf2_assets, &wav_assets);
     audio_context
         .store_new_program(PROGRAM_NO, &config, &mut loader)
@@ -40,26 +76,184 @@ fn configure_audio(
     audio_context.change_program(PROGRAM_NO).unwrap();
 }
 
+/// Play the key events, and cancel the notes scheduled ahead when every
+/// note is to be released
 fn play_key_events(
+    mut all_notes_off: EventReader<AllNotesOffEvent>,
     mut events: EventReader<KeyEvent>,
+    active_program: Res<ActiveProgram>,
+    mixer: Res<Mixer>,
+    bus: Res<Bus>,
     mut audio_context: ResMut<MidiGraphAudioContext>,
 ) -> Result<(), BevyError> {
+    if !all_notes_off.is_empty() {
+        all_notes_off.clear();
+        bus.cancel_scheduled();
+    }
     if events.is_empty() {
         return Ok(());
     }
     for event in events.read() {
         let event_channel = audio_context.get_event_sender();
-        event_channel.send(event.message.clone())?;
+        for message in route_key_event(&active_program, &mixer, event) {
+            match event.due_secs {
+                Some(secs) => bus.schedule(secs, message),
+                None => event_channel.send(message)?,
+            }
+        }
     }
     Ok(())
 }
 
-fn change_program(
+/// Release the sounding notes through the programs playing them, in the
+/// frame a program is requested and before it starts, as the keys are then
+/// routed to the new program
+fn release_notes_before_change(
     mut events: EventReader<StartProgramEvent>,
+    active_notes: Res<ActiveNotes>,
+    active_program: Res<ActiveProgram>,
+    mixer: Res<Mixer>,
     mut audio_context: ResMut<MidiGraphAudioContext>,
+) -> Result<(), BevyError> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    events.clear();
+    let event_channel = audio_context.get_event_sender();
+    for register in [KeyboardRegister::Lower, KeyboardRegister::Upper] {
+        for note in active_notes.notes(register).iter() {
+            let event = make_key_event(
+                register,
+                Event::NoteOff {
+                    note: *note,
+                    vel: 1.0,
+                },
+            );
+            for message in route_key_event(&active_program, &mixer, &event) {
+                event_channel.send(message)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Start the requested programs, or queue them until they have finished
+/// loading
+fn change_program(
+    mut events: EventReader<StartProgramEvent>,
+    programs: Res<ProgramAssets>,
+    mut active_program: ResMut<ActiveProgram>,
+    mut ensemble: ResMut<Ensemble>,
+    mut pending_programs: Local<Vec<(usize, ProgramTarget)>>,
 ) {
     for event in events.read() {
-        audio_context.change_program(event.program_no).unwrap();
-        println!("DID CHANGE PROGRAM: {}", event.program_no);
+        // A newer switch of the same target replaces one still waiting
+        if !matches!(event.target, ProgramTarget::Layer(_)) {
+            pending_programs.retain(|(_, target)| *target != event.target);
+        }
+        pending_programs.push((event.program_no, event.target));
+    }
+    if pending_programs.is_empty() {
+        return;
+    }
+    pending_programs.retain(|&(program_no, target)| {
+        if program_no != PROGRAM_NO {
+            match programs.get(program_no) {
+                Some(program) if program.is_ready() => {}
+                Some(program) if program.error.is_none() => return true,
+                _ => {
+                    warn!("Program {} is unavailable", program_no);
+                    return false;
+                }
+            }
+        }
+        active_program.start(program_no, target);
+        false
+    });
+    let registers = register_programs(&active_program);
+    if ensemble.registers != registers {
+        ensemble.registers = registers;
+    }
+}
+
+/// Play the active program, combined with the ensemble programs, through
+/// the output bus. Waits for the graphs of the combined programs to load.
+/// The composite program is only built again when the programs it combines
+/// change or reload, as building it cuts off the notes sounding.
+fn play_ensemble(
+    active_program: Res<ActiveProgram>,
+    ensemble: Res<Ensemble>,
+    bus: Res<Bus>,
+    effects: Res<Effects>,
+    programs: Res<ProgramAssets>,
+    graph_assets: Res<Assets<MidiGraph>>,
+    server: Res<AssetServer>,
+    midi_assets: Res<Assets<MidiFileSource>>,
+    sf2_assets: Res<Assets<Sf2FileSource>>,
+    wav_assets: Res<Assets<WaveFileSource>>,
+    mut audio_context: ResMut<MidiGraphAudioContext>,
+    mut built: Local<Option<(usize, BTreeSet<usize>, u64)>>,
+) {
+    let Some(program_no) = active_program.program_no else {
+        return;
+    };
+    let composite = (program_no, ensemble.programs(), programs.loads());
+    if built.as_ref() == Some(&composite) {
+        return;
+    }
+    let ensemble_programs = &composite.1;
+    let mut program_sources = vec![];
+    for program_no in std::iter::once(program_no).chain(ensemble_programs.iter().copied()) {
+        let Some(program) = programs.get(program_no) else {
+            warn!("Program {} is unavailable to the ensemble", program_no);
+            continue;
+        };
+        match graph_assets.get(&program.handle).map(program_source) {
+            Some(Ok(source)) => program_sources.push((program_no, source)),
+            Some(Err(error)) => warn!(
+                "Program {} cannot be combined with the ensemble: {}",
+                program_no, error
+            ),
+            // Tried again each frame until it loads
+            None if program.error.is_none() => return,
+            None => warn!("Program {} is unavailable to the ensemble", program_no),
+        }
+    }
+    *built = Some(composite.clone());
+    let (active, ensemble_sources) = match program_sources.split_first() {
+        Some(((active_no, active), ensemble_sources)) if *active_no == program_no => {
+            (active, ensemble_sources)
+        }
+        _ => {
+            warn!("Program {} cannot be played", program_no);
+            return;
+        }
+    };
+    let mut loader = GraphAssetLoader::new(&server, &midi_assets, &sf2_assets, &wav_assets);
+    let result = compose_program(active, ensemble_sources)
+        .into_iter()
+        .map(|(register, source)| {
+            serde_json::from_value::<NodeConfigData>(source).map(|config| (register, config))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| error.to_string())
+        .map(|parts| bus.program(parts, &effects))
+        .and_then(|config| {
+            audio_context
+                .store_new_program(COMPOSITE_PROGRAM_NO, &config, &mut loader)
+                .map_err(|error| format!("{:?}", error))
+        })
+        .and_then(|_| {
+            audio_context
+                .change_program(COMPOSITE_PROGRAM_NO)
+                .map_err(|error| format!("{:?}", error))
+        });
+    match result {
+        Ok(_) if ensemble_programs.is_empty() => println!("DID CHANGE PROGRAM: {}", program_no),
+        Ok(_) => println!(
+            "DID CHANGE PROGRAM: {} with ensemble {:?}",
+            program_no, ensemble_programs
+        ),
+        Err(error) => warn!("Failed changing to program {}: {}", program_no, error),
     }
 }
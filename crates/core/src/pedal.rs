@@ -0,0 +1,110 @@
+use crate::{
+    KeyEvent, KeyboardRegister, Pedal, PedalEvent,
+    input::make_key_event,
+    pipeline::{KeyPipeline, KeyPipelineSet},
+};
+use bevy::{platform::collections::HashSet, prelude::*};
+use bevy_midi_graph::midi::event::Event;
+
+pub struct PedalPlugin;
+
+impl Plugin for PedalPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Pedals>()
+            .add_systems(PreUpdate, apply_pedals.in_set(KeyPipelineSet::Sustain));
+    }
+}
+
+/// Pedal state and the notes it is holding.
+/// With sustain down every released note keeps sounding; with sostenuto down
+/// only the notes that were held when it was pressed keep sounding.
+#[derive(Resource, Default)]
+pub struct Pedals {
+    pub sustain: bool,
+    pub sostenuto: bool,
+    held: HashSet<(KeyboardRegister, u8)>,
+    sostenuto_notes: HashSet<(KeyboardRegister, u8)>,
+    sustained: HashSet<(KeyboardRegister, u8)>,
+}
+
+impl Pedals {
+    /// Whether a note has been released but is still sounding
+    pub fn is_sustained(&self, register: KeyboardRegister, note: u8) -> bool {
+        self.sustained.contains(&(register, note))
+    }
+
+    /// Forget every held and sustained note, leaving the pedals as they are
+    pub fn release_all(&mut self) {
+        self.held.clear();
+        self.sostenuto_notes.clear();
+        self.sustained.clear();
+    }
+
+    fn holds(&self, key: &(KeyboardRegister, u8)) -> bool {
+        self.sustain || (self.sostenuto && self.sostenuto_notes.contains(key))
+    }
+
+    fn release_unheld(&mut self, events: &mut Vec<KeyEvent>) {
+        let released: Vec<(KeyboardRegister, u8)> = self
+            .sustained
+            .iter()
+            .filter(|key| !self.holds(key))
+            .copied()
+            .collect();
+        for (register, note) in released {
+            self.sustained.remove(&(register, note));
+            events.push(make_key_event(register, Event::NoteOff { note, vel: 1.0 }));
+        }
+    }
+}
+
+fn apply_pedals(
+    mut pedal_events: EventReader<PedalEvent>,
+    mut pedals: ResMut<Pedals>,
+    mut pipeline: ResMut<KeyPipeline>,
+) {
+    if pipeline.events.is_empty() && pedal_events.is_empty() {
+        return;
+    }
+    let pedals = &mut *pedals;
+    let mut events = Vec::with_capacity(pipeline.events.len());
+    for event in pipeline.events.drain(..) {
+        match event.message.data {
+            Event::NoteOn { note, .. } => {
+                let key = (event.register, note);
+                if pedals.sustained.remove(&key) {
+                    events.push(make_key_event(
+                        event.register,
+                        Event::NoteOff { note, vel: 1.0 },
+                    ));
+                }
+                pedals.held.insert(key);
+            }
+            Event::NoteOff { note, .. } => {
+                let key = (event.register, note);
+                pedals.held.remove(&key);
+                if pedals.holds(&key) {
+                    pedals.sustained.insert(key);
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        events.push(event);
+    }
+    for event in pedal_events.read() {
+        match (event.pedal, event.down) {
+            (Pedal::Sustain, down) => pedals.sustain = down,
+            (Pedal::Sostenuto, true) => {
+                pedals.sostenuto = true;
+                pedals.sostenuto_notes = pedals.held.clone();
+            }
+            (Pedal::Sostenuto, false) => {
+                pedals.sostenuto = false;
+                pedals.sostenuto_notes.clear();
+            }
+        }
+        pedals.release_unheld(&mut events);
+    }
+    pipeline.events = events;
+}
@@ -0,0 +1,186 @@
+use crate::{
+    KeyboardRegister,
+    graphics::{KEY_HEIGHT, KeyWithNote, RestTranslation},
+    midi_file::{ChannelRouting, MidiSong, SongEvent},
+    player::{MidiPlayer, MidiPlayerSettings},
+};
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_midi_graph::midi::event::Event;
+
+const LOOKAHEAD_SECS: f64 = 3.0;
+const FALL_SPEED: f32 = 0.6;
+const WHITE_BAR_WIDTH: f32 = 0.16;
+const BLACK_BAR_WIDTH: f32 = 0.1;
+const BAR_DEPTH: f32 = 0.06;
+
+pub struct PianoRollPlugin;
+
+impl Plugin for PianoRollPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PianoRoll>()
+            .add_systems(Startup, create_roll_materials)
+            .add_systems(Update, (build_note_spans, update_falling_notes).chain());
+    }
+}
+
+/// A note of the loaded song, from the time it starts until it ends
+struct NoteSpan {
+    start_secs: f64,
+    end_secs: f64,
+    register: KeyboardRegister,
+    note: u8,
+}
+
+/// Notes of the loaded song shown falling toward the keys that play them.
+/// Bars are spawned only while their note is within sight.
+#[derive(Resource, Default)]
+struct PianoRoll {
+    song: AssetId<MidiSong>,
+    spans: Vec<NoteSpan>,
+    bars: HashMap<usize, Entity>,
+    mesh: Handle<Mesh>,
+    lower: Handle<StandardMaterial>,
+    upper: Handle<StandardMaterial>,
+}
+
+#[derive(Component)]
+struct FallingNote;
+
+fn create_roll_materials(
+    mut roll: ResMut<PianoRoll>,
+    mut material_assets: ResMut<Assets<StandardMaterial>>,
+    mut mesh_assets: ResMut<Assets<Mesh>>,
+) {
+    roll.mesh = mesh_assets.add(Cuboid::from_size(Vec3::ONE));
+    roll.lower = material_assets.add(StandardMaterial {
+        base_color: Color::srgba(0.3, 0.55, 0.95, 0.8),
+        alpha_mode: AlphaMode::Blend,
+        ..default()
+    });
+    roll.upper = material_assets.add(StandardMaterial {
+        base_color: Color::srgba(0.4, 0.85, 0.45, 0.8),
+        alpha_mode: AlphaMode::Blend,
+        ..default()
+    });
+}
+
+fn note_spans(song: &MidiSong, routing: &ChannelRouting) -> Vec<NoteSpan> {
+    let mut spans = vec![];
+    let mut started: HashMap<(u8, u8), f64> = HashMap::default();
+    for event in song.events.iter() {
+        let SongEvent::Note(data) = &event.data else {
+            continue;
+        };
+        let (note, start_secs) = match *data {
+            Event::NoteOn { note, .. } => {
+                started.entry((event.channel, note)).or_insert(event.secs);
+                continue;
+            }
+            Event::NoteOff { note, .. } => match started.remove(&(event.channel, note)) {
+                Some(start_secs) => (note, start_secs),
+                None => continue,
+            },
+            _ => continue,
+        };
+        spans.push(NoteSpan {
+            start_secs,
+            end_secs: event.secs,
+            register: routing.register_for(event.channel),
+            note,
+        });
+    }
+    let end_secs = song.duration_secs();
+    spans.extend(
+        started
+            .into_iter()
+            .map(|((channel, note), start_secs)| NoteSpan {
+                start_secs,
+                end_secs,
+                register: routing.register_for(channel),
+                note,
+            }),
+    );
+    spans
+}
+
+fn build_note_spans(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<MidiSong>>,
+    songs: Res<Assets<MidiSong>>,
+    player: Res<MidiPlayer>,
+    settings: Res<MidiPlayerSettings>,
+    mut roll: ResMut<PianoRoll>,
+) {
+    let id = player.song_id();
+    let song_changed = events.read().any(|event| {
+        event.is_added(id) || event.is_modified(id) || event.is_loaded_with_dependencies(id)
+    });
+    if id == roll.song && !song_changed && !settings.is_changed() {
+        return;
+    }
+    for (_, entity) in roll.bars.drain() {
+        commands.entity(entity).despawn();
+    }
+    roll.song = id;
+    roll.spans = songs
+        .get(id)
+        .map_or(vec![], |song| note_spans(song, &settings.routing));
+}
+
+/// Place the visible part of each note above its key, reaching the key as
+/// the note starts and shrinking into it while the note plays
+fn update_falling_notes(
+    mut commands: Commands,
+    player: Res<MidiPlayer>,
+    mut roll: ResMut<PianoRoll>,
+    key_query: Query<(&KeyWithNote, &RestTranslation)>,
+    mut bar_query: Query<&mut Transform, With<FallingNote>>,
+) {
+    let lanes: HashMap<(KeyboardRegister, u8), (Vec3, bool)> = key_query
+        .iter()
+        .map(|(key, rest)| ((key.register, key.note), (rest.0, key.black_key)))
+        .collect();
+    let now = player.position_secs;
+    let roll = roll.as_mut();
+    for (index, span) in roll.spans.iter().enumerate() {
+        let visible_start = span.start_secs.max(now);
+        let visible_end = span.end_secs.min(now + LOOKAHEAD_SECS);
+        let lane = lanes.get(&(span.register, span.note));
+        let (Some((rest, black_key)), true) = (lane, visible_end > visible_start) else {
+            if let Some(entity) = roll.bars.remove(&index) {
+                commands.entity(entity).despawn();
+            }
+            continue;
+        };
+        let key_top = rest.y + 0.5 * KEY_HEIGHT;
+        let bottom = key_top + (visible_start - now) as f32 * FALL_SPEED;
+        let height = (visible_end - visible_start) as f32 * FALL_SPEED;
+        let width = match black_key {
+            true => BLACK_BAR_WIDTH,
+            false => WHITE_BAR_WIDTH,
+        };
+        let transform = Transform::from_xyz(rest.x, bottom + 0.5 * height, rest.z)
+            .with_scale(Vec3::new(width, height, BAR_DEPTH));
+        if let Some(mut bar_transform) = roll
+            .bars
+            .get(&index)
+            .and_then(|entity| bar_query.get_mut(*entity).ok())
+        {
+            *bar_transform = transform;
+            continue;
+        }
+        let material = match span.register {
+            KeyboardRegister::Lower => roll.lower.clone(),
+            KeyboardRegister::Upper => roll.upper.clone(),
+        };
+        let entity = commands
+            .spawn((
+                FallingNote,
+                Mesh3d(roll.mesh.clone()),
+                MeshMaterial3d(material),
+                transform,
+            ))
+            .id();
+        roll.bars.insert(index, entity);
+    }
+}
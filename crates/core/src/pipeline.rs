@@ -0,0 +1,78 @@
+use crate::{KeyEvent, KeyInputEvent, KeySource};
+use bevy::prelude::*;
+
+pub struct PipelinePlugin;
+
+/// Stages that notes pass through on their way from input devices to the
+/// `KeyEvent`s heard and shown by the output and graphics plugins.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum KeyPipelineSet {
+    Input,
+    Collect,
+    Scale,
+    Chord,
+    Sustain,
+    Arpeggiate,
+    Rejoin,
+    Release,
+    Emit,
+}
+
+/// Key events collected this frame, transformed in place by each stage.
+/// Notes played from a song are held aside and rejoin the others after the
+/// stages that change notes, so that they play as written.
+#[derive(Resource, Default)]
+pub struct KeyPipeline {
+    pub events: Vec<KeyEvent>,
+    passing: Vec<KeyEvent>,
+}
+
+impl Plugin for PipelinePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<KeyPipeline>()
+            .configure_sets(
+                PreUpdate,
+                (
+                    KeyPipelineSet::Input,
+                    KeyPipelineSet::Collect,
+                    KeyPipelineSet::Scale,
+                    KeyPipelineSet::Chord,
+                    KeyPipelineSet::Sustain,
+                    KeyPipelineSet::Arpeggiate,
+                    KeyPipelineSet::Rejoin,
+                    KeyPipelineSet::Release,
+                    KeyPipelineSet::Emit,
+                )
+                    .chain(),
+            )
+            .add_systems(
+                PreUpdate,
+                (
+                    collect_key_input.in_set(KeyPipelineSet::Collect),
+                    rejoin_passing_events.in_set(KeyPipelineSet::Rejoin),
+                    emit_key_events.in_set(KeyPipelineSet::Emit),
+                ),
+            );
+    }
+}
+
+fn collect_key_input(mut events: EventReader<KeyInputEvent>, mut pipeline: ResMut<KeyPipeline>) {
+    for event in events.read() {
+        match event.source {
+            KeySource::Song => pipeline.passing.push(event.0.clone()),
+            _ => pipeline.events.push(event.0.clone()),
+        }
+    }
+}
+
+fn rejoin_passing_events(mut pipeline: ResMut<KeyPipeline>) {
+    let pipeline = pipeline.as_mut();
+    pipeline.events.append(&mut pipeline.passing);
+}
+
+fn emit_key_events(mut pipeline: ResMut<KeyPipeline>, mut events: EventWriter<KeyEvent>) {
+    if pipeline.events.is_empty() {
+        return;
+    }
+    events.write_batch(pipeline.events.drain(..));
+}
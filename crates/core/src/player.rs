@@ -0,0 +1,299 @@
+use crate::{
+    KeyEvent, KeyInputEvent, KeySource, KeyboardRegister, ProgramTarget, StartProgramEvent,
+    input::make_key_event,
+    midi_file::{ChannelRouting, MidiSong, MidiSongLoader, SongEvent},
+    pipeline::KeyPipelineSet,
+};
+use bevy::{platform::collections::HashSet, prelude::*};
+use bevy_midi_graph::midi::event::Event;
+use std::path::Path;
+
+const SEEK_SECS: f64 = 5.0;
+const TEMPO_STEP: f32 = 0.1;
+const TEMPO_MIN: f32 = 0.25;
+const TEMPO_MAX: f32 = 2.0;
+
+pub struct MidiPlayerPlugin;
+
+impl Plugin for MidiPlayerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<MidiSong>()
+            .init_asset_loader::<MidiSongLoader>()
+            .init_resource::<MidiPlayerSettings>()
+            .init_resource::<MidiPlayer>()
+            .add_systems(
+                PreUpdate,
+                (load_song, control_player, advance_player)
+                    .chain()
+                    .in_set(KeyPipelineSet::Input),
+            );
+        #[cfg(not(target_arch = "wasm32"))]
+        app.add_systems(
+            PreUpdate,
+            open_dropped_song
+                .before(control_player)
+                .in_set(KeyPipelineSet::Input),
+        );
+    }
+}
+
+/// The song to play and how its channels are played.
+/// `song` is the asset path of a `.mid` file; on desktop a file dropped onto
+/// the window replaces it. With `follow_program_changes` on, program changes
+/// in the file switch the program of the register their channel plays on.
+#[derive(Resource)]
+pub struct MidiPlayerSettings {
+    pub song: String,
+    pub routing: ChannelRouting,
+    pub follow_program_changes: bool,
+}
+
+impl Default for MidiPlayerSettings {
+    fn default() -> Self {
+        Self {
+            song: "songs/demo.mid".to_owned(),
+            routing: ChannelRouting::default(),
+            follow_program_changes: false,
+        }
+    }
+}
+
+/// Playback state of the loaded song.
+/// Notes are played through the key pipeline, so they sound and light up
+/// the keyboard just like notes played by hand. Scale lock, chords, sustain
+/// and the arpeggiator leave them as written.
+#[derive(Resource)]
+pub struct MidiPlayer {
+    pub song_name: String,
+    pub playing: bool,
+    pub position_secs: f64,
+    pub tempo_scale: f32,
+    /// Playback waits here, for the player to catch up in practice mode
+    pub(crate) hold_at_secs: Option<f64>,
+    /// Registers left for the player to play in practice mode
+    pub(crate) silent_registers: Vec<KeyboardRegister>,
+    song_path: String,
+    handle: Handle<MidiSong>,
+    next_event: usize,
+    sounding: HashSet<(KeyboardRegister, u8)>,
+}
+
+impl Default for MidiPlayer {
+    fn default() -> Self {
+        Self {
+            song_name: String::new(),
+            playing: false,
+            position_secs: 0.0,
+            tempo_scale: 1.0,
+            hold_at_secs: None,
+            silent_registers: vec![],
+            song_path: String::new(),
+            handle: Handle::default(),
+            next_event: 0,
+            sounding: HashSet::default(),
+        }
+    }
+}
+
+impl MidiPlayer {
+    pub fn song<'a>(&self, songs: &'a Assets<MidiSong>) -> Option<&'a MidiSong> {
+        songs.get(&self.handle)
+    }
+
+    pub fn song_id(&self) -> AssetId<MidiSong> {
+        self.handle.id()
+    }
+
+    fn change_song(
+        &mut self,
+        path: String,
+        handle: Handle<MidiSong>,
+        note_events: &mut EventWriter<KeyInputEvent>,
+    ) {
+        self.release_notes(note_events);
+        self.song_name = song_name(&path);
+        self.song_path = path;
+        self.handle = handle;
+        self.playing = false;
+        self.position_secs = 0.0;
+        self.next_event = 0;
+    }
+
+    /// Move to a point in the song, stopping any notes it was playing
+    pub(crate) fn seek(
+        &mut self,
+        song: &MidiSong,
+        secs: f64,
+        note_events: &mut EventWriter<KeyInputEvent>,
+    ) {
+        self.release_notes(note_events);
+        self.position_secs = secs.clamp(0.0, song.duration_secs());
+        self.next_event = song
+            .events
+            .partition_point(|event| event.secs < self.position_secs);
+    }
+
+    fn release_notes(&mut self, note_events: &mut EventWriter<KeyInputEvent>) {
+        for (register, note) in self.sounding.drain() {
+            note_events.write(song_key_event(register, Event::NoteOff { note, vel: 1.0 }));
+        }
+    }
+}
+
+fn song_key_event(register: KeyboardRegister, data: Event) -> KeyInputEvent {
+    KeyInputEvent(KeyEvent {
+        source: KeySource::Song,
+        ..make_key_event(register, data)
+    })
+}
+
+fn song_name(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map_or(path.to_owned(), |name| name.to_string_lossy().into_owned())
+}
+
+fn load_song(
+    server: Res<AssetServer>,
+    settings: Res<MidiPlayerSettings>,
+    mut player: ResMut<MidiPlayer>,
+    mut note_events: EventWriter<KeyInputEvent>,
+) {
+    if !settings.is_changed() || player.song_path == settings.song {
+        return;
+    }
+    let handle = server.load(&settings.song);
+    player.change_song(settings.song.clone(), handle, &mut note_events);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn open_dropped_song(
+    mut events: EventReader<bevy::window::FileDragAndDrop>,
+    mut songs: ResMut<Assets<MidiSong>>,
+    mut player: ResMut<MidiPlayer>,
+    mut note_events: EventWriter<KeyInputEvent>,
+) {
+    use crate::midi_file::read_events;
+    use bevy::window::FileDragAndDrop;
+
+    for event in events.read() {
+        let FileDragAndDrop::DroppedFile { path_buf, .. } = event else {
+            continue;
+        };
+        let is_midi = path_buf
+            .extension()
+            .is_some_and(|extension| extension == "mid" || extension == "midi");
+        if !is_midi {
+            continue;
+        }
+        let events = std::fs::read(path_buf)
+            .map_err(|error| error.to_string())
+            .and_then(|bytes| read_events(&bytes));
+        match events {
+            Ok(events) => {
+                let handle = songs.add(MidiSong { events });
+                let path = path_buf.to_string_lossy().into_owned();
+                println!("DID OPEN SONG: {}", path);
+                player.change_song(path, handle, &mut note_events);
+            }
+            Err(error) => warn!("Failed opening song {}: {}", path_buf.display(), error),
+        }
+    }
+}
+
+fn control_player(
+    inputs: Res<ButtonInput<KeyCode>>,
+    songs: Res<Assets<MidiSong>>,
+    mut player: ResMut<MidiPlayer>,
+    mut note_events: EventWriter<KeyInputEvent>,
+) {
+    let Some(song) = player.song(&songs) else {
+        return;
+    };
+    for key in inputs.get_just_pressed() {
+        match key {
+            KeyCode::NumpadEnter => {
+                player.playing = !player.playing;
+                if !player.playing {
+                    player.release_notes(&mut note_events);
+                }
+            }
+            KeyCode::Numpad5 => {
+                player.playing = false;
+                player.seek(song, 0.0, &mut note_events);
+            }
+            KeyCode::NumpadDivide => {
+                let secs = player.position_secs - SEEK_SECS;
+                player.seek(song, secs, &mut note_events);
+            }
+            KeyCode::NumpadMultiply => {
+                let secs = player.position_secs + SEEK_SECS;
+                player.seek(song, secs, &mut note_events);
+            }
+            KeyCode::Numpad2 => {
+                player.tempo_scale = (player.tempo_scale - TEMPO_STEP).max(TEMPO_MIN);
+            }
+            KeyCode::Numpad8 => {
+                player.tempo_scale = (player.tempo_scale + TEMPO_STEP).min(TEMPO_MAX);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn advance_player(
+    time: Res<Time>,
+    songs: Res<Assets<MidiSong>>,
+    settings: Res<MidiPlayerSettings>,
+    mut player: ResMut<MidiPlayer>,
+    mut note_events: EventWriter<KeyInputEvent>,
+    mut program_events: EventWriter<StartProgramEvent>,
+) {
+    if !player.playing {
+        return;
+    }
+    let Some(song) = player.song(&songs) else {
+        return;
+    };
+    let player = player.as_mut();
+    player.position_secs += time.delta_secs_f64() * player.tempo_scale as f64;
+    if let Some(hold_at_secs) = player.hold_at_secs {
+        player.position_secs = player.position_secs.min(hold_at_secs);
+    }
+    while let Some(event) = song.events.get(player.next_event) {
+        if event.secs > player.position_secs {
+            break;
+        }
+        player.next_event += 1;
+        let register = settings.routing.register_for(event.channel);
+        match &event.data {
+            SongEvent::Note(_) if player.silent_registers.contains(&register) => {}
+            SongEvent::Note(data) => {
+                match *data {
+                    Event::NoteOn { note, .. } => {
+                        player.sounding.insert((register, note));
+                    }
+                    Event::NoteOff { note, .. } => {
+                        player.sounding.remove(&(register, note));
+                    }
+                    _ => {}
+                }
+                note_events.write(song_key_event(register, data.clone()));
+            }
+            SongEvent::Program(program_no) if settings.follow_program_changes => {
+                program_events.write(StartProgramEvent {
+                    program_no: *program_no,
+                    target: ProgramTarget::Register(register),
+                });
+            }
+            SongEvent::Program(_) => {}
+        }
+    }
+    if player.next_event >= song.events.len() {
+        println!("DID FINISH SONG: {}", player.song_name);
+        player.release_notes(&mut note_events);
+        player.playing = false;
+        player.position_secs = 0.0;
+        player.next_event = 0;
+    }
+}
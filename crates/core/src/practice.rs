@@ -0,0 +1,322 @@
+use crate::{
+    KeyEvent, KeyInputEvent, KeySource, KeyboardRegister,
+    bus::Bus,
+    midi_file::{ChannelRouting, MidiSong, SongEvent},
+    player::{MidiPlayer, MidiPlayerSettings},
+};
+use bevy::{platform::collections::HashSet, prelude::*};
+use bevy_midi_graph::midi::event::Event;
+
+const PRACTICE_KEY: KeyCode = KeyCode::Backquote;
+const CHORD_WINDOW_SECS: f64 = 0.05;
+const EARLY_TOLERANCE_SECS: f64 = 0.25;
+
+pub struct PracticePlugin;
+
+impl Plugin for PracticePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<WrongNoteEvent>()
+            .init_resource::<Practice>()
+            .add_systems(
+                Update,
+                (
+                    select_practice_hand,
+                    build_practice_chords,
+                    check_practice_notes,
+                )
+                    .chain(),
+            );
+    }
+}
+
+/// Which hands the player practises; the song plays the other hand
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PracticeHand {
+    #[default]
+    Off,
+    Both,
+    Left,
+    Right,
+}
+
+impl PracticeHand {
+    fn next(self) -> Self {
+        match self {
+            PracticeHand::Off => PracticeHand::Both,
+            PracticeHand::Both => PracticeHand::Left,
+            PracticeHand::Left => PracticeHand::Right,
+            PracticeHand::Right => PracticeHand::Off,
+        }
+    }
+
+    /// The left hand plays the lower register and the right hand the upper
+    pub fn registers(self) -> Vec<KeyboardRegister> {
+        match self {
+            PracticeHand::Off => vec![],
+            PracticeHand::Both => vec![KeyboardRegister::Lower, KeyboardRegister::Upper],
+            PracticeHand::Left => vec![KeyboardRegister::Lower],
+            PracticeHand::Right => vec![KeyboardRegister::Upper],
+        }
+    }
+}
+
+/// A played note that was not part of the chord being waited for
+#[derive(Event, Debug)]
+pub struct WrongNoteEvent {
+    pub register: KeyboardRegister,
+    pub note: u8,
+}
+
+/// Notes that start together in the practised part of the song
+struct Chord {
+    secs: f64,
+    notes: HashSet<u8>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PracticeStats {
+    pub chords: usize,
+    pub correct_notes: usize,
+    pub wrong_notes: usize,
+    total_delay_secs: f32,
+}
+
+impl PracticeStats {
+    /// Share of played notes that were correct
+    pub fn accuracy(&self) -> f32 {
+        match self.correct_notes + self.wrong_notes {
+            0 => 1.0,
+            played => self.correct_notes as f32 / played as f32,
+        }
+    }
+
+    /// Average time between a chord falling due and it being completed
+    pub fn mean_delay_secs(&self) -> f32 {
+        match self.chords {
+            0 => 0.0,
+            chords => self.total_delay_secs / chords as f32,
+        }
+    }
+}
+
+/// A wait-for-me practice session.
+/// Playback holds at each chord of the practised hands until every note of
+/// the chord has been played, then moves on to the next one. Arpeggiated
+/// notes count from the time they are due to sound.
+#[derive(Resource, Default)]
+pub struct Practice {
+    pub hand: PracticeHand,
+    pub stats: PracticeStats,
+    /// Results of the last finished session
+    pub summary: Option<PracticeStats>,
+    song: AssetId<MidiSong>,
+    chords: Vec<Chord>,
+    next_chord: usize,
+    pressed: HashSet<u8>,
+    due_since_secs: Option<f32>,
+    last_position_secs: f64,
+}
+
+impl Practice {
+    pub fn progress(&self) -> (usize, usize) {
+        (self.next_chord, self.chords.len())
+    }
+
+    fn restart_at(&mut self, position_secs: f64) {
+        self.next_chord = self
+            .chords
+            .partition_point(|chord| chord.secs < position_secs - CHORD_WINDOW_SECS);
+        self.pressed.clear();
+        self.due_since_secs = None;
+        self.last_position_secs = position_secs;
+        if self.next_chord == 0 {
+            self.stats = PracticeStats::default();
+        }
+    }
+
+    fn finish_session(&mut self) {
+        if self.stats.chords == 0 {
+            return;
+        }
+        let stats = self.stats;
+        println!(
+            "DID FINISH PRACTICE: {} chords, {:.0}% accuracy, {:.2}s average delay",
+            stats.chords,
+            stats.accuracy() * 100.0,
+            stats.mean_delay_secs()
+        );
+        self.summary = Some(stats);
+        self.stats = PracticeStats::default();
+    }
+}
+
+fn practice_chords(
+    song: &MidiSong,
+    routing: &ChannelRouting,
+    registers: &[KeyboardRegister],
+) -> Vec<Chord> {
+    let mut chords: Vec<Chord> = vec![];
+    for event in song.events.iter() {
+        let SongEvent::Note(Event::NoteOn { note, .. }) = event.data else {
+            continue;
+        };
+        if !registers.contains(&routing.register_for(event.channel)) {
+            continue;
+        }
+        match chords.last_mut() {
+            Some(chord) if event.secs - chord.secs <= CHORD_WINDOW_SECS => {
+                chord.notes.insert(note);
+            }
+            _ => chords.push(Chord {
+                secs: event.secs,
+                notes: HashSet::from_iter([note]),
+            }),
+        }
+    }
+    chords
+}
+
+/// Cycle through the practice hands, rewinding the song for a new session
+fn select_practice_hand(
+    inputs: Res<ButtonInput<KeyCode>>,
+    songs: Res<Assets<MidiSong>>,
+    mut practice: ResMut<Practice>,
+    mut player: ResMut<MidiPlayer>,
+    mut note_events: EventWriter<KeyInputEvent>,
+) {
+    if !inputs.just_pressed(PRACTICE_KEY) {
+        return;
+    }
+    practice.finish_session();
+    practice.hand = practice.hand.next();
+    // Rebuild chords for the new hands
+    practice.song = AssetId::default();
+    player.silent_registers = practice.hand.registers();
+    player.hold_at_secs = None;
+    if let Some(song) = player.song(&songs) {
+        player.playing = false;
+        player.seek(song, 0.0, &mut note_events);
+    }
+    println!("DID SELECT PRACTICE: {:?}", practice.hand);
+}
+
+fn build_practice_chords(
+    mut events: EventReader<AssetEvent<MidiSong>>,
+    songs: Res<Assets<MidiSong>>,
+    settings: Res<MidiPlayerSettings>,
+    mut player: ResMut<MidiPlayer>,
+    mut practice: ResMut<Practice>,
+) {
+    let id = player.song_id();
+    let song_changed = events.read().any(|event| {
+        event.is_added(id) || event.is_modified(id) || event.is_loaded_with_dependencies(id)
+    });
+    if id == practice.song && !song_changed && !settings.is_changed() {
+        return;
+    }
+    practice.song = id;
+    practice.chords = match (practice.hand, songs.get(id)) {
+        (PracticeHand::Off, _) | (_, None) => vec![],
+        (hand, Some(song)) => practice_chords(song, &settings.routing, &hand.registers()),
+    };
+    practice.restart_at(player.position_secs);
+    player.hold_at_secs = practice
+        .chords
+        .get(practice.next_chord)
+        .map(|chord| chord.secs);
+}
+
+/// Match the notes played against the chord being waited for, and move on
+/// once it is complete
+fn check_practice_notes(
+    mut key_events: EventReader<KeyEvent>,
+    time: Res<Time>,
+    bus: Res<Bus>,
+    mut player: ResMut<MidiPlayer>,
+    mut practice: ResMut<Practice>,
+    mut wrong_note_events: EventWriter<WrongNoteEvent>,
+) {
+    if practice.hand == PracticeHand::Off {
+        key_events.clear();
+        return;
+    }
+    let practice = practice.as_mut();
+    let position_secs = player.position_secs;
+    let hold_at_secs = player.hold_at_secs.unwrap_or(f64::MAX);
+    if position_secs < practice.last_position_secs || position_secs > hold_at_secs {
+        // The song was rewound or seeked past the chord being waited for
+        practice.restart_at(position_secs);
+    }
+    practice.last_position_secs = position_secs;
+
+    let registers = practice.hand.registers();
+    let now_secs = bus.audio_secs();
+    for event in key_events.read() {
+        let Event::NoteOn { note, .. } = event.data else {
+            continue;
+        };
+        let played = matches!(event.source, KeySource::Live | KeySource::Arp);
+        if !played || !registers.contains(&event.register) {
+            continue;
+        }
+        // Where the song will be when a note scheduled ahead sounds
+        let note_secs = match event.due_secs {
+            Some(due_secs) => {
+                position_secs + (due_secs - now_secs).max(0.0) * player.tempo_scale as f64
+            }
+            None => position_secs,
+        };
+        let chord_in_reach = practice.chords.get(practice.next_chord).filter(|chord| {
+            note_secs >= chord.secs - EARLY_TOLERANCE_SECS && chord.notes.contains(&note)
+        });
+        match chord_in_reach {
+            Some(_) => {
+                if practice.pressed.insert(note) {
+                    practice.stats.correct_notes += 1;
+                }
+            }
+            None => {
+                practice.stats.wrong_notes += 1;
+                wrong_note_events.write(WrongNoteEvent {
+                    register: event.register,
+                    note,
+                });
+            }
+        }
+    }
+
+    let Some(chord) = practice.chords.get(practice.next_chord) else {
+        player.hold_at_secs = None;
+        return;
+    };
+    if position_secs >= chord.secs && practice.due_since_secs.is_none() {
+        practice.due_since_secs = Some(time.elapsed_secs());
+    }
+    if !chord
+        .notes
+        .iter()
+        .all(|note| practice.pressed.contains(note))
+    {
+        player.hold_at_secs = Some(chord.secs);
+        return;
+    }
+    let delay_secs = match practice.due_since_secs {
+        Some(due_since_secs) => time.elapsed_secs() - due_since_secs,
+        None => (chord.secs - position_secs) as f32 / player.tempo_scale,
+    };
+    practice.stats.chords += 1;
+    practice.stats.total_delay_secs += delay_secs;
+    practice.next_chord += 1;
+    practice.pressed.clear();
+    practice.due_since_secs = None;
+    if practice.stats.chords == 1 {
+        practice.summary = None;
+    }
+    match practice.chords.get(practice.next_chord) {
+        Some(next_chord) => player.hold_at_secs = Some(next_chord.secs),
+        None => {
+            player.hold_at_secs = None;
+            practice.finish_session();
+        }
+    }
+}
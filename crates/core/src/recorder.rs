@@ -0,0 +1,419 @@
+use crate::{
+    ActiveProgram, KeyEvent, KeyboardRegister, ProgramTarget, StartProgramEvent, bus::Bus,
+    midi_file::midi_program_for, save,
+};
+use bevy::{platform::collections::HashSet, prelude::*};
+use bevy_midi_graph::midi::event::Event;
+use midly::{
+    Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind,
+    num::{u4, u7, u15, u24, u28},
+};
+use std::time::Duration;
+
+const TICKS_PER_BEAT: u16 = 480;
+const MICROS_PER_BEAT: u32 = 500_000;
+const RECORD_KEY: KeyCode = KeyCode::Insert;
+
+pub struct RecorderPlugin;
+
+impl Plugin for RecorderPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Recorder>()
+            .init_resource::<RecorderSettings>()
+            .add_systems(Update, (toggle_recording, record_events).chain());
+    }
+}
+
+/// Where finished recordings are written on desktop. Recordings saved into
+/// the asset folder can be referenced by program graphs like any other MIDI
+/// file. On the web, recordings are downloaded by the browser instead.
+#[derive(Resource)]
+pub struct RecorderSettings {
+    pub save_dir: String,
+}
+
+impl Default for RecorderSettings {
+    fn default() -> Self {
+        Self {
+            save_dir: "recordings".to_owned(),
+        }
+    }
+}
+
+/// A performance being captured, as timestamped messages for each register.
+/// Notes scheduled ahead, like the arpeggiator's, are recorded at the time
+/// they are due to sound.
+#[derive(Resource, Default)]
+pub struct Recorder {
+    pub recording: bool,
+    pub last_take: Option<RecordedTake>,
+    started: Duration,
+    lower: Vec<(Duration, RecordedMessage)>,
+    upper: Vec<(Duration, RecordedMessage)>,
+    sounding: HashSet<(KeyboardRegister, u8)>,
+}
+
+/// A finished recording, encoded as a Standard MIDI File
+#[derive(Clone)]
+pub struct RecordedTake {
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum RecordedMessage {
+    NoteOn {
+        note: u8,
+        vel: u8,
+    },
+    NoteOff {
+        note: u8,
+        vel: u8,
+    },
+    /// The MIDI program, counted from 0
+    Program(u8),
+}
+
+impl Recorder {
+    fn start(&mut self, now: Duration, active_program: &ActiveProgram) {
+        *self = Self {
+            recording: true,
+            last_take: self.last_take.take(),
+            started: now,
+            ..default()
+        };
+        for register in [KeyboardRegister::Lower, KeyboardRegister::Upper] {
+            if let Some(program_no) = active_program.program_for(register) {
+                self.record_program(now, register, program_no);
+            }
+        }
+    }
+
+    /// Stop recording, releasing any notes still held, and export the take
+    fn stop(&mut self, now: Duration) -> &RecordedTake {
+        // After any notes that were due to start later
+        let end = self
+            .lower
+            .iter()
+            .chain(self.upper.iter())
+            .map(|(time, _)| *time)
+            .fold(now, Duration::max);
+        for (register, note) in self.sounding.drain().collect::<Vec<_>>() {
+            self.track_mut(register)
+                .push((end, RecordedMessage::NoteOff { note, vel: 64 }));
+        }
+        self.recording = false;
+        let bytes = self.to_smf_bytes();
+        self.last_take.insert(RecordedTake {
+            name: save::timestamped_file_name("recording", "mid"),
+            bytes,
+        })
+    }
+
+    /// Time since recording started
+    pub fn elapsed(&self, now: Duration) -> Duration {
+        now.saturating_sub(self.started)
+    }
+
+    fn track_mut(&mut self, register: KeyboardRegister) -> &mut Vec<(Duration, RecordedMessage)> {
+        match register {
+            KeyboardRegister::Lower => &mut self.lower,
+            KeyboardRegister::Upper => &mut self.upper,
+        }
+    }
+
+    /// Record a key event at the time it sounds
+    fn record_key_event(&mut self, at: Duration, event: &KeyEvent) {
+        let message = match event.data {
+            Event::NoteOn { note, vel } => {
+                if !self.sounding.insert((event.register, note)) {
+                    return;
+                }
+                RecordedMessage::NoteOn {
+                    note,
+                    vel: midi_velocity(vel).max(1),
+                }
+            }
+            Event::NoteOff { note, vel } => {
+                // Notes held before recording started have no NoteOn to end
+                if !self.sounding.remove(&(event.register, note)) {
+                    return;
+                }
+                RecordedMessage::NoteOff {
+                    note,
+                    vel: midi_velocity(vel),
+                }
+            }
+            _ => return,
+        };
+        self.insert(event.register, at, message);
+    }
+
+    fn record_program(&mut self, now: Duration, register: KeyboardRegister, program_no: usize) {
+        let message = RecordedMessage::Program(midi_program_for(program_no));
+        self.insert(register, now, message);
+    }
+
+    /// Add a message to a register's track after those at the same time or
+    /// earlier, as notes due later may already be recorded
+    fn insert(&mut self, register: KeyboardRegister, at: Duration, message: RecordedMessage) {
+        let track = self.track_mut(register);
+        let index = track.partition_point(|(time, _)| *time <= at);
+        track.insert(index, (at, message));
+    }
+
+    /// Encode the take as a type-1 Standard MIDI File with one track per
+    /// register, the lower register on channel 1 and the upper on channel 2
+    fn to_smf_bytes(&self) -> Vec<u8> {
+        let tracks = [(&self.lower, 0), (&self.upper, 1)]
+            .into_iter()
+            .enumerate()
+            .map(|(index, (messages, channel))| {
+                let mut track = vec![];
+                if index == 0 {
+                    let tempo = MetaMessage::Tempo(u24::new(MICROS_PER_BEAT));
+                    track.push(TrackEvent {
+                        delta: u28::new(0),
+                        kind: TrackEventKind::Meta(tempo),
+                    });
+                }
+                let mut last_tick = 0;
+                for (time, message) in messages.iter() {
+                    let tick = self.ticks_at(*time);
+                    let message = match *message {
+                        RecordedMessage::NoteOn { note, vel } => MidiMessage::NoteOn {
+                            key: u7::new(note),
+                            vel: u7::new(vel),
+                        },
+                        RecordedMessage::NoteOff { note, vel } => MidiMessage::NoteOff {
+                            key: u7::new(note),
+                            vel: u7::new(vel),
+                        },
+                        RecordedMessage::Program(program) => MidiMessage::ProgramChange {
+                            program: u7::new(program),
+                        },
+                    };
+                    track.push(TrackEvent {
+                        delta: u28::new(tick - last_tick),
+                        kind: TrackEventKind::Midi {
+                            channel: u4::new(channel),
+                            message,
+                        },
+                    });
+                    last_tick = tick;
+                }
+                track.push(TrackEvent {
+                    delta: u28::new(0),
+                    kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+                });
+                track
+            })
+            .collect();
+        let smf = Smf {
+            header: Header::new(Format::Parallel, Timing::Metrical(u15::new(TICKS_PER_BEAT))),
+            tracks,
+        };
+        let mut bytes = vec![];
+        if let Err(error) = smf.write_std(&mut bytes) {
+            warn!("Failed encoding recording: {}", error);
+        }
+        bytes
+    }
+
+    fn ticks_at(&self, time: Duration) -> u32 {
+        let micros = self.elapsed(time).as_micros();
+        (micros * TICKS_PER_BEAT as u128 / MICROS_PER_BEAT as u128) as u32
+    }
+}
+
+fn midi_velocity(vel: f32) -> u8 {
+    (vel.clamp(0.0, 1.0) * 127.0).round() as u8
+}
+
+fn toggle_recording(
+    inputs: Res<ButtonInput<KeyCode>>,
+    time: Res<Time>,
+    settings: Res<RecorderSettings>,
+    active_program: Res<ActiveProgram>,
+    mut recorder: ResMut<Recorder>,
+) {
+    // Shift+Insert renders the last take instead
+    if !inputs.just_pressed(RECORD_KEY)
+        || inputs.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight])
+    {
+        return;
+    }
+    if !recorder.recording {
+        recorder.start(time.elapsed(), &active_program);
+        return;
+    }
+    let take = recorder.stop(time.elapsed());
+    match save::save_file(&settings.save_dir, &take.name, "audio/midi", &take.bytes) {
+        Ok(location) => info!("Saved recording to {}", location),
+        Err(error) => warn!("Failed saving recording: {}", error),
+    }
+}
+
+fn record_events(
+    mut key_events: EventReader<KeyEvent>,
+    mut program_events: EventReader<StartProgramEvent>,
+    time: Res<Time>,
+    bus: Res<Bus>,
+    mut recorder: ResMut<Recorder>,
+) {
+    if !recorder.recording {
+        key_events.clear();
+        program_events.clear();
+        return;
+    }
+    let now = time.elapsed();
+    for event in program_events.read() {
+        // Layers are not recorded, as each track holds one program
+        let registers = match event.target {
+            ProgramTarget::Keyboard => vec![KeyboardRegister::Lower, KeyboardRegister::Upper],
+            ProgramTarget::Register(register) => vec![register],
+            ProgramTarget::Layer(_) => vec![],
+        };
+        for register in registers {
+            recorder.record_program(now, register, event.program_no);
+        }
+    }
+    let now_secs = bus.audio_secs();
+    for event in key_events.read() {
+        let at = match event.due_secs {
+            Some(due_secs) => now + Duration::from_secs_f64((due_secs - now_secs).max(0.0)),
+            None => now,
+        };
+        recorder.record_key_event(at, event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        input::make_key_event,
+        midi_file::{SongEvent, read_events},
+    };
+
+    fn at_secs(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    #[test]
+    fn take_reads_back_from_its_midi_file() {
+        let mut recorder = Recorder::default();
+        let active_program = ActiveProgram {
+            program_no: Some(3),
+            upper: Some(5),
+            ..default()
+        };
+        recorder.start(at_secs(10.0), &active_program);
+        for (secs, register, data) in [
+            (
+                10.5,
+                KeyboardRegister::Lower,
+                Event::NoteOn { note: 48, vel: 1.0 },
+            ),
+            (
+                11.0,
+                KeyboardRegister::Upper,
+                Event::NoteOn { note: 72, vel: 0.5 },
+            ),
+            (
+                11.5,
+                KeyboardRegister::Lower,
+                Event::NoteOff { note: 48, vel: 1.0 },
+            ),
+        ] {
+            recorder.record_key_event(at_secs(secs), &make_key_event(register, data));
+        }
+        // The upper note is still held, so stopping releases it
+        let take = recorder.stop(at_secs(12.0)).clone();
+
+        let smf = Smf::parse(&take.bytes).unwrap();
+        assert_eq!(smf.header.format, Format::Parallel);
+        assert_eq!(smf.tracks.len(), 2);
+        assert!(matches!(
+            smf.tracks[0][0].kind,
+            TrackEventKind::Meta(MetaMessage::Tempo(tempo)) if tempo.as_int() == MICROS_PER_BEAT
+        ));
+        assert!(smf.tracks[1].iter().any(|event| matches!(
+            event.kind,
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { key, vel },
+                ..
+            } if key.as_int() == 72 && vel.as_int() == 64
+        )));
+
+        let played: Vec<_> = read_events(&take.bytes)
+            .unwrap()
+            .iter()
+            .map(|event| {
+                let millis = (event.secs * 1000.0).round() as u32;
+                let (kind, value) = match event.data {
+                    SongEvent::Program(program_no) => ("program", program_no as u8),
+                    SongEvent::Note(Event::NoteOn { note, .. }) => ("on", note),
+                    SongEvent::Note(Event::NoteOff { note, .. }) => ("off", note),
+                    SongEvent::Note(_) => ("other", 0),
+                };
+                (millis, event.channel, kind, value)
+            })
+            .collect();
+        assert_eq!(
+            played,
+            vec![
+                (0, 0, "program", 3),
+                (0, 1, "program", 5),
+                (500, 0, "on", 48),
+                (1000, 1, "on", 72),
+                (1500, 0, "off", 48),
+                (2000, 1, "off", 72),
+            ]
+        );
+    }
+
+    #[test]
+    fn take_tracks_keep_register_channels_and_program_numbers() {
+        let mut recorder = Recorder::default();
+        let active_program = ActiveProgram {
+            lower: Some(1),
+            upper: Some(3),
+            ..default()
+        };
+        recorder.start(at_secs(0.0), &active_program);
+        recorder.record_program(at_secs(1.0), KeyboardRegister::Upper, 2);
+        let take = recorder.stop(at_secs(2.0)).clone();
+
+        // Track 0 plays the lower register on channel 0, and track 1 the upper
+        // on channel 1. MIDI counts programs from 0, the manifest from 1.
+        let smf = Smf::parse(&take.bytes).unwrap();
+        let tracks: Vec<Vec<_>> = smf
+            .tracks
+            .iter()
+            .map(|track| {
+                track
+                    .iter()
+                    .filter_map(|event| match event.kind {
+                        TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::ProgramChange { program },
+                        } => Some((channel.as_int(), program.as_int())),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(tracks, vec![vec![(0, 0)], vec![(1, 2), (1, 1)]]);
+
+        let programs: Vec<_> = read_events(&take.bytes)
+            .unwrap()
+            .iter()
+            .filter_map(|event| match event.data {
+                SongEvent::Program(program_no) => Some((event.channel, program_no)),
+                SongEvent::Note(_) => None,
+            })
+            .collect();
+        assert_eq!(programs, vec![(0, 1), (1, 3), (1, 2)]);
+    }
+}
@@ -0,0 +1,467 @@
+use crate::{
+    ActiveProgram, KeyboardRegister, ProgramLoadFailedEvent, StartProgramEvent,
+    assets::{AssetsPlugin, ProgramAssets},
+    effects::{EffectsChain, EffectsSettings},
+    input::make_key_event,
+    midi_file::{ChannelRouting, SongEvent, read_events},
+    mixer::Mixer,
+    recorder::{Recorder, RecorderSettings},
+    save,
+};
+use bevy::{asset::LoadState, prelude::*};
+use bevy_midi_graph::midi::event::Event;
+use bevy_midi_graph::{
+    GraphAssetLoader, MidiFileSource, MidiGraph, MidiGraphPlugin, Sf2FileSource, WaveFileSource,
+    midi::{
+        consts::{CHANNEL_COUNT, PLAYBACK_SAMPLE_RATE},
+        node::NodeConfigData,
+    },
+};
+use hound::{WavSpec, WavWriter};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Cursor,
+};
+
+const BUFFER_FRAMES: usize = 1024;
+const RELEASE_TAIL_SECS: f64 = 2.0;
+const RENDER_KEY: KeyCode = KeyCode::Insert;
+/// How long a headless render waits for its program before giving up
+const HEADLESS_TIMEOUT_SECS: f32 = 60.0;
+
+/// Renders performances from within the app, such as the last recorded
+/// take with Shift+Insert.
+/// Each register of a render plays through its own instance of its program,
+/// switched by the program changes on its channel and mixed at the gains
+/// and pans of the mixer, then through the effects of the program it
+/// started with. Program changes to programs that do not exist are ignored,
+/// as when playing. Layers, the metronome, the looper and the modulation
+/// wheel's vibrato are not rendered, as a take does not record them.
+pub struct OfflineRenderPlugin;
+
+impl Plugin for OfflineRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<OfflineRenderEvent>()
+            .add_event::<OfflineRenderFinishedEvent>()
+            .add_systems(Update, (request_take_render, render_offline).chain());
+    }
+}
+
+/// Runs without a window or keyboard input, renders one performance and
+/// exits, reporting failure through the exit code. Gives up if the program
+/// has not loaded within a minute. Renders do not play through an audio
+/// device, so this needs none.
+pub struct HeadlessRenderPlugin {
+    pub job: OfflineRenderEvent,
+}
+
+impl Plugin for HeadlessRenderPlugin {
+    fn build(&self, app: &mut App) {
+        let job = self.job.clone();
+        let file_name = self.job.file_name.clone();
+        app.add_event::<StartProgramEvent>()
+            .add_event::<ProgramLoadFailedEvent>()
+            .add_event::<OfflineRenderEvent>()
+            .add_event::<OfflineRenderFinishedEvent>()
+            .init_resource::<ActiveProgram>()
+            .add_plugins((MidiGraphPlugin, AssetsPlugin))
+            .add_systems(
+                Startup,
+                move |mut events: EventWriter<OfflineRenderEvent>| {
+                    events.write(job.clone());
+                },
+            )
+            .add_systems(
+                Update,
+                (
+                    render_offline,
+                    time_out_render(file_name),
+                    exit_when_rendered,
+                )
+                    .chain(),
+            );
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WavSampleFormat {
+    #[default]
+    Int16,
+    Int24,
+    Float,
+}
+
+/// Render a Standard MIDI File through a program faster than real time,
+/// without an audio device, and save the result as a WAV file
+#[derive(Event, Clone, Debug)]
+pub struct OfflineRenderEvent {
+    pub midi: Vec<u8>,
+    pub program_no: usize,
+    pub mixer: Mixer,
+    pub save_dir: String,
+    pub file_name: String,
+    pub sample_format: WavSampleFormat,
+}
+
+/// The outcome of a render: where the WAV file was saved, or why it failed
+#[derive(Event, Debug)]
+pub struct OfflineRenderFinishedEvent {
+    pub file_name: String,
+    pub result: Result<String, String>,
+}
+
+/// Shift+Insert renders the last recorded take with the active program
+fn request_take_render(
+    inputs: Res<ButtonInput<KeyCode>>,
+    recorder: Res<Recorder>,
+    settings: Res<RecorderSettings>,
+    active_program: Res<ActiveProgram>,
+    mixer: Res<Mixer>,
+    mut events: EventWriter<OfflineRenderEvent>,
+) {
+    if !inputs.just_pressed(RENDER_KEY)
+        || !inputs.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight])
+        || recorder.recording
+    {
+        return;
+    }
+    let (Some(take), Some(program_no)) = (&recorder.last_take, active_program.program_no) else {
+        return;
+    };
+    events.write(OfflineRenderEvent {
+        midi: take.bytes.clone(),
+        program_no,
+        mixer: mixer.clone(),
+        save_dir: settings.save_dir.clone(),
+        file_name: take.name.replace(".mid", ".wav"),
+        sample_format: WavSampleFormat::Int16,
+    });
+}
+
+/// Render requested performances once their programs have loaded
+fn render_offline(
+    mut events: EventReader<OfflineRenderEvent>,
+    mut finished_events: EventWriter<OfflineRenderFinishedEvent>,
+    programs: Res<ProgramAssets>,
+    server: Res<AssetServer>,
+    graph_assets: Res<Assets<MidiGraph>>,
+    midi_assets: Res<Assets<MidiFileSource>>,
+    sf2_assets: Res<Assets<Sf2FileSource>>,
+    wav_assets: Res<Assets<WaveFileSource>>,
+    mut pending: Local<Vec<OfflineRenderEvent>>,
+) {
+    pending.extend(events.read().cloned());
+    pending.retain(|job| {
+        let result = match programs.get(job.program_no) {
+            Some(program) if program.is_ready() => {
+                let mut configs = BTreeMap::new();
+                for program_no in std::iter::once(job.program_no).chain(song_programs(&job.midi)) {
+                    match programs.get(program_no) {
+                        Some(program) if program.is_ready() => {
+                            let Some(graph) = graph_assets.get(&program.handle) else {
+                                return true;
+                            };
+                            configs.insert(program_no, &graph.config);
+                        }
+                        Some(program) if program.error.is_none() => return true,
+                        _ => {}
+                    }
+                }
+                let mut loader =
+                    GraphAssetLoader::new(&server, &midi_assets, &sf2_assets, &wav_assets);
+                render_performance(
+                    job.program_no,
+                    &configs,
+                    &program.effects,
+                    &job.mixer,
+                    &mut loader,
+                    &job.midi,
+                )
+                .and_then(|samples| encode_wav(&samples, job.sample_format))
+                .and_then(|bytes| {
+                    save::save_file(&job.save_dir, &job.file_name, "audio/wav", &bytes)
+                })
+            }
+            Some(program) => match &program.error {
+                Some(error) => Err(error.to_string()),
+                None => return true,
+            },
+            None if programs.programs.is_empty() => {
+                match server.load_state(programs.manifest.id()) {
+                    LoadState::Failed(error) => {
+                        Err(format!("Failed loading the program manifest: {}", error))
+                    }
+                    _ => return true,
+                }
+            }
+            None => Err(format!("Program {} does not exist", job.program_no)),
+        };
+        match &result {
+            Ok(location) => println!("DID RENDER: {}", location),
+            Err(error) => warn!("Failed rendering {}: {}", job.file_name, error),
+        }
+        finished_events.write(OfflineRenderFinishedEvent {
+            file_name: job.file_name.clone(),
+            result,
+        });
+        false
+    });
+}
+
+/// Fail the render if it has not finished in time, such as when its program
+/// never loads
+fn time_out_render(
+    file_name: String,
+) -> impl FnMut(Res<Time<Real>>, EventWriter<OfflineRenderFinishedEvent>, Local<bool>) {
+    move |time, mut finished_events, mut timed_out| {
+        if *timed_out || time.elapsed_secs() < HEADLESS_TIMEOUT_SECS {
+            return;
+        }
+        *timed_out = true;
+        let error = format!("Timed out after {} seconds", HEADLESS_TIMEOUT_SECS);
+        warn!("Failed rendering {}: {}", file_name, error);
+        finished_events.write(OfflineRenderFinishedEvent {
+            file_name: file_name.clone(),
+            result: Err(error),
+        });
+    }
+}
+
+fn exit_when_rendered(
+    mut events: EventReader<OfflineRenderFinishedEvent>,
+    mut quit_signal: EventWriter<AppExit>,
+) {
+    if let Some(event) = events.read().last() {
+        quit_signal.write(match event.result {
+            Ok(_) => AppExit::Success,
+            Err(_) => AppExit::error(),
+        });
+    }
+}
+
+/// The programs a MIDI file changes to
+fn song_programs(midi: &[u8]) -> Vec<usize> {
+    read_events(midi)
+        .into_iter()
+        .flatten()
+        .filter_map(|event| match event.data {
+            SongEvent::Program(program_no) => Some(program_no),
+            SongEvent::Note(_) => None,
+        })
+        .collect()
+}
+
+/// Play a MIDI file into fresh instances of the programs' graphs, one for
+/// each register and program it plays, mixed and through the effects,
+/// returning interleaved samples. Each register starts on `program_no`.
+fn render_performance(
+    program_no: usize,
+    configs: &BTreeMap<usize, &NodeConfigData>,
+    effects: &EffectsSettings,
+    mixer: &Mixer,
+    loader: &mut GraphAssetLoader,
+    midi: &[u8],
+) -> Result<Vec<f32>, String> {
+    let routing = ChannelRouting::default();
+    let events: Vec<_> = read_events(midi)?
+        .into_iter()
+        .map(|event| (event.secs, routing.register_for(event.channel), event.data))
+        .collect();
+    let mut to_node = |program_no: usize| {
+        let config = configs
+            .get(&program_no)
+            .ok_or_else(|| format!("Program {} is unavailable", program_no))?;
+        config
+            .0
+            .to_node(loader)
+            .map_err(|error| format!("{:?}", error))
+    };
+    let registers = [KeyboardRegister::Lower, KeyboardRegister::Upper];
+    let mut playing: HashMap<_, _> = registers
+        .into_iter()
+        .map(|register| (register, program_no))
+        .collect();
+    let mut voices = vec![];
+    for register in registers {
+        voices.push((register, program_no, to_node(program_no)?));
+    }
+    let mut chain = EffectsChain::new();
+    chain.set_settings(effects);
+    let sample_rate = PLAYBACK_SAMPLE_RATE as f64;
+    let end_secs = events.last().map_or(0.0, |(secs, _, _)| *secs) + RELEASE_TAIL_SECS;
+    let total_frames = (end_secs * sample_rate).ceil() as usize;
+    let mut samples = vec![0.0; total_frames * CHANNEL_COUNT];
+    let mut voice_buffer = vec![];
+    let mut events = events.into_iter().peekable();
+    let mut rendered_frames = 0;
+    while rendered_frames < total_frames {
+        let mut next_event_frame = total_frames;
+        while let Some((secs, register, data)) = events.peek() {
+            let frame = (secs * sample_rate) as usize;
+            if frame > rendered_frames {
+                next_event_frame = next_event_frame.min(frame);
+                break;
+            }
+            match data {
+                SongEvent::Program(program_no) if configs.contains_key(program_no) => {
+                    let exists = voices.iter().any(|(voice_register, voice_program, _)| {
+                        voice_register == register && voice_program == program_no
+                    });
+                    if !exists {
+                        voices.push((*register, *program_no, to_node(*program_no)?));
+                    }
+                    playing.insert(*register, *program_no);
+                }
+                SongEvent::Program(_) => {}
+                SongEvent::Note(data) => {
+                    // Notes start on the register's program, and are released
+                    // on every program they might still sound on
+                    let starts = matches!(data, Event::NoteOn { .. });
+                    let event = make_key_event(*register, data.clone());
+                    for (voice_register, voice_program, node) in voices.iter_mut() {
+                        if voice_register == register
+                            && (!starts || *voice_program == playing[register])
+                        {
+                            node.on_event(&event.message);
+                        }
+                    }
+                }
+            }
+            events.next();
+        }
+        let end_frame = next_event_frame.min(rendered_frames + BUFFER_FRAMES);
+        let buffer = &mut samples[rendered_frames * CHANNEL_COUNT..end_frame * CHANNEL_COUNT];
+        for (register, _, node) in voices.iter_mut() {
+            voice_buffer.clear();
+            voice_buffer.resize(buffer.len(), 0.0);
+            node.fill_buffer(&mut voice_buffer);
+            let gains = mixer.output_gains(Some(*register));
+            let frame_pairs = voice_buffer
+                .chunks_exact(CHANNEL_COUNT)
+                .zip(buffer.chunks_exact_mut(CHANNEL_COUNT));
+            for (frame, mixed) in frame_pairs {
+                for (channel, (sample, mixed)) in frame.iter().zip(mixed.iter_mut()).enumerate() {
+                    *mixed += sample * gains[channel.min(1)];
+                }
+            }
+        }
+        chain.process(buffer);
+        rendered_frames = end_frame;
+    }
+    Ok(samples)
+}
+
+fn encode_wav(samples: &[f32], sample_format: WavSampleFormat) -> Result<Vec<u8>, String> {
+    let (bits_per_sample, hound_format) = match sample_format {
+        WavSampleFormat::Int16 => (16, hound::SampleFormat::Int),
+        WavSampleFormat::Int24 => (24, hound::SampleFormat::Int),
+        WavSampleFormat::Float => (32, hound::SampleFormat::Float),
+    };
+    let spec = WavSpec {
+        channels: CHANNEL_COUNT as u16,
+        sample_rate: PLAYBACK_SAMPLE_RATE as u32,
+        bits_per_sample,
+        sample_format: hound_format,
+    };
+    let mut cursor = Cursor::new(vec![]);
+    let mut writer = WavWriter::new(&mut cursor, spec).map_err(|error| error.to_string())?;
+    for sample in samples.iter().copied() {
+        let clipped = sample.clamp(-1.0, 1.0);
+        match sample_format {
+            WavSampleFormat::Int16 => writer.write_sample((clipped * i16::MAX as f32) as i16),
+            WavSampleFormat::Int24 => writer.write_sample((clipped * 8_388_607.0) as i32),
+            WavSampleFormat::Float => writer.write_sample(sample),
+        }
+        .map_err(|error| error.to_string())?;
+    }
+    writer.finalize().map_err(|error| error.to_string())?;
+    Ok(cursor.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+    use bevy_midi_graph::midi::{event::Balance, node::SquareWave};
+    use hound::WavReader;
+
+    const DEMO_SONG: &[u8] = include_bytes!("../../../assets/songs/demo.mid");
+    /// The demo song's last note ends on tick 15350, at 480 ticks a half
+    /// second beat
+    const DEMO_SONG_SECS: f64 = 15350.0 / 960.0;
+
+    #[test]
+    fn renders_midi_file_to_wav() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+            .init_asset::<MidiFileSource>()
+            .init_asset::<Sf2FileSource>()
+            .init_asset::<WaveFileSource>();
+        let samples = app
+            .world_mut()
+            .run_system_once(
+                |server: Res<AssetServer>,
+                 midi_assets: Res<Assets<MidiFileSource>>,
+                 sf2_assets: Res<Assets<Sf2FileSource>>,
+                 wav_assets: Res<Assets<WaveFileSource>>| {
+                    let config = NodeConfigData(Box::new(SquareWave {
+                        node_id: None,
+                        balance: Balance::Both,
+                        amplitude: 0.125,
+                        duty_cycle: 0.25,
+                    }));
+                    let mut loader =
+                        GraphAssetLoader::new(&server, &midi_assets, &sf2_assets, &wav_assets);
+                    render_performance(
+                        1,
+                        &BTreeMap::from([(1, &config)]),
+                        &EffectsSettings::default(),
+                        &Mixer::default(),
+                        &mut loader,
+                        DEMO_SONG,
+                    )
+                },
+            )
+            .unwrap()
+            .unwrap();
+        assert!(samples.iter().any(|sample| *sample != 0.0));
+
+        let bytes = encode_wav(&samples, WavSampleFormat::Int16).unwrap();
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[8..12], b"WAVE");
+        let reader = WavReader::new(Cursor::new(bytes)).unwrap();
+        let spec = reader.spec();
+        assert_eq!(spec.channels as usize, CHANNEL_COUNT);
+        assert_eq!(spec.sample_rate, PLAYBACK_SAMPLE_RATE as u32);
+        assert_eq!(spec.bits_per_sample, 16);
+        let frames = ((DEMO_SONG_SECS + RELEASE_TAIL_SECS) * PLAYBACK_SAMPLE_RATE as f64).ceil();
+        assert_eq!(reader.len() as usize, frames as usize * CHANNEL_COUNT);
+    }
+
+    /// Runs the render subcommand's app with no audio device, as on a build
+    /// machine, so this fails if rendering ever comes to need one
+    #[test]
+    fn headless_render_needs_no_audio_device() {
+        let save_dir = std::env::temp_dir().join("shining-piano-render-test");
+        let job = OfflineRenderEvent {
+            midi: DEMO_SONG.to_vec(),
+            program_no: 1,
+            mixer: Mixer::default(),
+            save_dir: save_dir.display().to_string(),
+            file_name: "demo.wav".to_owned(),
+            sample_format: WavSampleFormat::Int16,
+        };
+        let exit = App::new()
+            .add_plugins((
+                MinimalPlugins,
+                AssetPlugin {
+                    file_path: "../../assets".to_owned(),
+                    ..default()
+                },
+                HeadlessRenderPlugin { job },
+            ))
+            .run();
+        assert_eq!(exit, AppExit::Success);
+        assert!(save_dir.join("demo.wav").exists());
+    }
+}
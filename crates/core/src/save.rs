@@ -0,0 +1,102 @@
+#[cfg(not(target_arch = "wasm32"))]
+pub use desktop::*;
+#[cfg(target_arch = "wasm32")]
+pub use web::*;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod desktop {
+    use std::{
+        fs,
+        path::Path,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    /// A file name that is unique to the second it was made
+    pub fn timestamped_file_name(prefix: &str, extension: &str) -> String {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        format!("{}-{}.{}", prefix, secs, extension)
+    }
+
+    /// Write the file into `dir`, returning where it was written
+    pub fn save_file(
+        dir: &str,
+        file_name: &str,
+        _mime_type: &str,
+        bytes: &[u8],
+    ) -> Result<String, String> {
+        let path = Path::new(dir).join(file_name);
+        fs::create_dir_all(dir).map_err(|error| error.to_string())?;
+        fs::write(&path, bytes).map_err(|error| error.to_string())?;
+        Ok(path.display().to_string())
+    }
+
+    /// The settings kept in the file at `key`, if there are any
+    pub fn load_settings(key: &str) -> Option<String> {
+        fs::read_to_string(key).ok()
+    }
+
+    /// Keep the settings in the file at `key`
+    pub fn store_settings(key: &str, contents: &str) -> Result<(), String> {
+        fs::write(key, contents).map_err(|error| error.to_string())
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod web {
+    use wasm_bindgen::{JsCast, JsValue};
+    use web_sys::{Blob, BlobPropertyBag, HtmlAnchorElement, Storage, Url};
+
+    /// A file name that is unique to the second it was made
+    pub fn timestamped_file_name(prefix: &str, extension: &str) -> String {
+        let secs = js_sys::Date::now() as u64 / 1000;
+        format!("{}-{}.{}", prefix, secs, extension)
+    }
+
+    /// Offer the file as a browser download, returning its name
+    pub fn save_file(
+        _dir: &str,
+        file_name: &str,
+        mime_type: &str,
+        bytes: &[u8],
+    ) -> Result<String, String> {
+        let error_text = |error: JsValue| format!("{:?}", error);
+        let window = web_sys::window().ok_or("No window")?;
+        let document = window.document().ok_or("No document")?;
+
+        let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(bytes));
+        let options = BlobPropertyBag::new();
+        options.set_type(mime_type);
+        let blob =
+            Blob::new_with_u8_array_sequence_and_options(&parts, &options).map_err(error_text)?;
+        let url = Url::create_object_url_with_blob(&blob).map_err(error_text)?;
+        let anchor = document
+            .create_element("a")
+            .map_err(error_text)?
+            .dyn_into::<HtmlAnchorElement>()
+            .map_err(|element| error_text(element.into()))?;
+        anchor.set_href(&url);
+        anchor.set_download(file_name);
+        anchor.click();
+        Url::revoke_object_url(&url).map_err(error_text)?;
+        Ok(file_name.to_owned())
+    }
+
+    fn local_storage() -> Option<Storage> {
+        web_sys::window()?.local_storage().ok().flatten()
+    }
+
+    /// The settings kept in local storage under `key`, if there are any
+    pub fn load_settings(key: &str) -> Option<String> {
+        local_storage()?.get_item(key).ok().flatten()
+    }
+
+    /// Keep the settings in local storage under `key`
+    pub fn store_settings(key: &str, contents: &str) -> Result<(), String> {
+        local_storage()
+            .ok_or("No local storage")?
+            .set_item(key, contents)
+            .map_err(|error| format!("{:?}", error))
+    }
+}
@@ -0,0 +1,239 @@
+use crate::{
+    AllNotesOffEvent, KeyboardRegister,
+    pipeline::{KeyPipeline, KeyPipelineSet},
+};
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_midi_graph::midi::event::Event;
+
+const LOCK_KEY: KeyCode = KeyCode::Numpad0;
+const ROOT_KEY: KeyCode = KeyCode::NumpadDecimal;
+
+pub struct ScaleLockPlugin;
+
+impl Plugin for ScaleLockPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ScaleLock>().add_systems(
+            PreUpdate,
+            (control_scale_lock, lock_to_scale)
+                .chain()
+                .in_set(KeyPipelineSet::Scale),
+        );
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScaleLockMode {
+    #[default]
+    Off,
+    /// Out-of-scale notes play the nearest note of the scale
+    Remap,
+    /// Out-of-scale notes do not play
+    Block,
+}
+
+impl ScaleLockMode {
+    fn next(self) -> Self {
+        match self {
+            ScaleLockMode::Off => ScaleLockMode::Remap,
+            ScaleLockMode::Remap => ScaleLockMode::Block,
+            ScaleLockMode::Block => ScaleLockMode::Off,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Scale {
+    #[default]
+    Major,
+    Minor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+    Custom,
+}
+
+impl Scale {
+    fn next(self) -> Self {
+        match self {
+            Scale::Major => Scale::Minor,
+            Scale::Minor => Scale::Dorian,
+            Scale::Dorian => Scale::Phrygian,
+            Scale::Phrygian => Scale::Lydian,
+            Scale::Lydian => Scale::Mixolydian,
+            Scale::Mixolydian => Scale::Locrian,
+            Scale::Locrian => Scale::MajorPentatonic,
+            Scale::MajorPentatonic => Scale::MinorPentatonic,
+            Scale::MinorPentatonic => Scale::Blues,
+            Scale::Blues => Scale::Custom,
+            Scale::Custom => Scale::Major,
+        }
+    }
+}
+
+/// Keeps played notes in the key of a scale.
+/// Numpad0 cycles the lock through off, remapping and blocking, and with
+/// Shift cycles the scale. NumpadDecimal raises the root a semitone, or
+/// lowers it with Shift. `custom` holds the semitones above the root of the
+/// custom scale.
+#[derive(Resource)]
+pub struct ScaleLock {
+    pub mode: ScaleLockMode,
+    pub scale: Scale,
+    pub root: u8,
+    pub custom: Vec<u8>,
+    /// Notes played for each held key
+    held: HashMap<(KeyboardRegister, u8), Option<u8>>,
+    /// How many held keys are playing each note
+    sounding: HashMap<(KeyboardRegister, u8), usize>,
+}
+
+impl Default for ScaleLock {
+    fn default() -> Self {
+        Self {
+            mode: ScaleLockMode::Off,
+            scale: Scale::Major,
+            root: 0,
+            custom: vec![0, 2, 3, 5, 7, 9, 10],
+            held: HashMap::default(),
+            sounding: HashMap::default(),
+        }
+    }
+}
+
+impl ScaleLock {
+    /// Semitones above the root of each note of the scale
+    pub fn intervals(&self) -> &[u8] {
+        match self.scale {
+            Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Scale::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            Scale::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            Scale::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Scale::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            Scale::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Scale::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Scale::Blues => &[0, 3, 5, 6, 7, 10],
+            Scale::Custom => &self.custom,
+        }
+    }
+
+    pub fn in_scale(&self, note: u8) -> bool {
+        let degree = (note + 12 - self.root % 12) % 12;
+        self.intervals()
+            .iter()
+            .any(|interval| interval % 12 == degree)
+    }
+
+    /// The note a key plays with the lock on, if any
+    pub fn lock(&self, note: u8) -> Option<u8> {
+        match self.mode {
+            ScaleLockMode::Off => Some(note),
+            _ if self.in_scale(note) => Some(note),
+            ScaleLockMode::Block => None,
+            // Prefer the note below when both neighbours are in the scale
+            ScaleLockMode::Remap => (1..12)
+                .flat_map(|distance| [note.checked_sub(distance), note.checked_add(distance)])
+                .flatten()
+                .find(|candidate| *candidate < 128 && self.in_scale(*candidate)),
+        }
+    }
+
+    /// The note to start for a pressed key, unless it is blocked or another
+    /// held key is already playing it
+    fn press(&mut self, register: KeyboardRegister, key_note: u8) -> Option<u8> {
+        let locked = self.lock(key_note);
+        self.held.insert((register, key_note), locked);
+        let note = locked?;
+        let count = self.sounding.entry((register, note)).or_default();
+        *count += 1;
+        (*count == 1).then_some(note)
+    }
+
+    /// The note to stop for a released key, once no other held key is
+    /// playing it
+    fn release(&mut self, register: KeyboardRegister, key_note: u8) -> Option<u8> {
+        let Some(locked) = self.held.remove(&(register, key_note)) else {
+            return Some(key_note);
+        };
+        let note = locked?;
+        let Some(count) = self.sounding.get_mut(&(register, note)) else {
+            return Some(note);
+        };
+        *count -= 1;
+        if *count > 0 {
+            return None;
+        }
+        self.sounding.remove(&(register, note));
+        Some(note)
+    }
+}
+
+fn control_scale_lock(inputs: Res<ButtonInput<KeyCode>>, mut scale_lock: ResMut<ScaleLock>) {
+    let shift = inputs.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    if inputs.just_pressed(LOCK_KEY) {
+        match shift {
+            true => scale_lock.scale = scale_lock.scale.next(),
+            false => scale_lock.mode = scale_lock.mode.next(),
+        }
+    }
+    if inputs.just_pressed(ROOT_KEY) {
+        scale_lock.root = match shift {
+            true => (scale_lock.root + 11) % 12,
+            false => (scale_lock.root + 1) % 12,
+        };
+    }
+}
+
+/// Remap or drop out-of-scale notes, releasing each key's note as it was
+/// played even if the scale has changed since. Keys remapped to the same
+/// note share it, and it stops when the last of them is released.
+fn lock_to_scale(
+    mut all_notes_off: EventReader<AllNotesOffEvent>,
+    mut scale_lock: ResMut<ScaleLock>,
+    mut pipeline: ResMut<KeyPipeline>,
+) {
+    if !all_notes_off.is_empty() {
+        all_notes_off.clear();
+        scale_lock.held.clear();
+        scale_lock.sounding.clear();
+    }
+    if pipeline.events.is_empty()
+        || (scale_lock.mode == ScaleLockMode::Off && scale_lock.held.is_empty())
+    {
+        return;
+    }
+    // Held keys are not shown, so following them is not a change
+    let scale_lock = scale_lock.bypass_change_detection();
+    let mut events = Vec::with_capacity(pipeline.events.len());
+    for mut event in pipeline.events.drain(..) {
+        // Keys pressed while others are held are followed even with the lock
+        // off, in case they play a note a held key was remapped to
+        let locked = match event.message.data {
+            Event::NoteOn { note, .. }
+                if scale_lock.mode != ScaleLockMode::Off || !scale_lock.held.is_empty() =>
+            {
+                scale_lock.press(event.register, note)
+            }
+            Event::NoteOff { note, .. } => scale_lock.release(event.register, note),
+            _ => {
+                events.push(event);
+                continue;
+            }
+        };
+        let Some(locked) = locked else {
+            continue;
+        };
+        match &mut event.message.data {
+            Event::NoteOn { note, .. } | Event::NoteOff { note, .. } => *note = locked,
+            _ => {}
+        }
+        events.push(event);
+    }
+    pipeline.events = events;
+}
@@ -0,0 +1,110 @@
+use crate::{
+    Dynamics, Settings,
+    mixer::Mixer,
+    save::{load_settings, store_settings},
+};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+pub struct SettingsStorePlugin;
+
+impl Plugin for SettingsStorePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SettingsStore>()
+            .add_systems(PreStartup, restore_settings)
+            .add_systems(Last, keep_settings);
+    }
+}
+
+/// Where the settings are kept between runs: a file on the desktop, or
+/// local storage in the browser. Restored values the controls could not
+/// have set are left at their defaults, so an edited or corrupted file
+/// cannot stop the app from starting.
+#[derive(Resource)]
+pub struct SettingsStore {
+    pub key: String,
+    /// The settings as last loaded or stored
+    kept: String,
+}
+
+impl SettingsStore {
+    pub fn new(key: &str) -> Self {
+        Self {
+            key: key.to_owned(),
+            kept: String::new(),
+        }
+    }
+}
+
+impl Default for SettingsStore {
+    fn default() -> Self {
+        Self::new("shining-piano.settings.json")
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredSettings<S, D, M> {
+    settings: S,
+    dynamics: D,
+    mixer: M,
+}
+
+fn restore_settings(
+    mut store: ResMut<SettingsStore>,
+    mut settings: ResMut<Settings>,
+    mut dynamics: ResMut<Dynamics>,
+    mut mixer: ResMut<Mixer>,
+) {
+    let Some(contents) = load_settings(&store.key) else {
+        return;
+    };
+    match serde_json::from_str::<StoredSettings<Settings, Dynamics, Mixer>>(&contents) {
+        Ok(mut stored) => {
+            let problems = [
+                stored.settings.reset_invalid(),
+                stored.dynamics.reset_invalid(),
+                stored.mixer.reset_invalid(),
+            ];
+            for problem in problems.iter().flatten() {
+                warn!("Ignored stored setting from {}: {}", store.key, problem);
+            }
+            *settings = stored.settings;
+            *dynamics = stored.dynamics;
+            *mixer = stored.mixer;
+            store.kept = contents;
+            info!("Restored settings from {}", store.key);
+        }
+        Err(error) => warn!("Failed restoring settings from {}: {}", store.key, error),
+    }
+}
+
+/// Store the settings whenever they change
+fn keep_settings(
+    mut store: ResMut<SettingsStore>,
+    settings: Res<Settings>,
+    dynamics: Res<Dynamics>,
+    mixer: Res<Mixer>,
+) {
+    if !settings.is_changed() && !dynamics.is_changed() && !mixer.is_changed() {
+        return;
+    }
+    let stored = StoredSettings {
+        settings: settings.as_ref(),
+        dynamics: dynamics.as_ref(),
+        mixer: mixer.as_ref(),
+    };
+    let contents = match serde_json::to_string_pretty(&stored) {
+        Ok(contents) => contents,
+        Err(error) => {
+            warn!("Failed storing settings: {}", error);
+            return;
+        }
+    };
+    if contents == store.kept {
+        return;
+    }
+    match store_settings(&store.key, &contents) {
+        Ok(_) => store.kept = contents,
+        Err(error) => warn!("Failed storing settings to {}: {}", store.key, error),
+    }
+}
@@ -0,0 +1,103 @@
+use crate::{
+    KeyInputEvent, KeyboardRegister, graphics::KeyWithNote, input::make_key_event,
+    pipeline::KeyPipelineSet,
+};
+use bevy::{
+    picking::{
+        hover::HoverMap,
+        mesh_picking::MeshPickingPlugin,
+        pointer::{PointerId, PointerPress},
+    },
+    platform::collections::HashMap,
+    prelude::*,
+};
+use bevy_midi_graph::midi::event::Event;
+
+const TOUCH_VELOCITY_MIN: f32 = 0.3;
+const TOUCH_VELOCITY_MAX: f32 = 1.0;
+
+pub struct TouchPlugin;
+
+impl Plugin for TouchPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<MeshPickingPlugin>() {
+            app.add_plugins(MeshPickingPlugin);
+        }
+        app.add_systems(PreUpdate, play_touched_keys.in_set(KeyPipelineSet::Input));
+    }
+}
+
+/// Velocity of a key pressed at a point, louder towards its front edge
+fn touch_velocity(key: &KeyWithNote, transform: &GlobalTransform, position: Vec3) -> f32 {
+    let local = transform.affine().inverse().transform_point3(position);
+    let front = (local.z / key.depth() + 0.5).clamp(0.0, 1.0);
+    TOUCH_VELOCITY_MIN + (TOUCH_VELOCITY_MAX - TOUCH_VELOCITY_MIN) * front
+}
+
+/// Play the keys under each pressed mouse button or touch. Dragging across
+/// the keys plays each in turn, and every touch plays its own key. A key
+/// held by several pointers sounds until the last lets go.
+fn play_touched_keys(
+    pointers: Query<(&PointerId, &PointerPress)>,
+    hover_map: Res<HoverMap>,
+    keys: Query<(&KeyWithNote, &GlobalTransform)>,
+    mut note_events: EventWriter<KeyInputEvent>,
+    mut touched: Local<HashMap<PointerId, (KeyboardRegister, u8)>>,
+) {
+    let mut released = vec![];
+    let mut pressed = vec![];
+    for (pointer_id, press) in pointers.iter() {
+        let hit = press
+            .is_primary_pressed()
+            .then(|| hover_map.get(pointer_id))
+            .flatten()
+            .and_then(|hits| {
+                hits.iter()
+                    .filter_map(|(entity, hit)| Some((keys.get(*entity).ok()?, hit)))
+                    .min_by(|(_, a), (_, b)| a.depth.total_cmp(&b.depth))
+            });
+        let target = hit.map(|((key, _), _)| (key.register, key.note));
+        let current = touched.get(pointer_id).copied();
+        if target == current {
+            continue;
+        }
+        if let Some(current) = current {
+            touched.remove(pointer_id);
+            released.push(current);
+        }
+        if let Some(((key, transform), hit)) = hit {
+            let vel = hit.position.map_or(TOUCH_VELOCITY_MAX, |position| {
+                touch_velocity(key, transform, position)
+            });
+            touched.insert(*pointer_id, (key.register, key.note));
+            pressed.push((key.register, key.note, vel));
+        }
+    }
+    // Touches that have ended no longer have a pointer
+    touched.retain(|pointer_id, held| {
+        let exists = pointers.iter().any(|(id, _)| id == pointer_id);
+        if !exists {
+            released.push(*held);
+        }
+        exists
+    });
+
+    let holders =
+        |held: (KeyboardRegister, u8)| touched.values().filter(|other| **other == held).count();
+    for (register, note) in released {
+        if holders((register, note)) == 0 {
+            note_events.write(KeyInputEvent(make_key_event(
+                register,
+                Event::NoteOff { note, vel: 1.0 },
+            )));
+        }
+    }
+    for (register, note, vel) in pressed {
+        if holders((register, note)) == 1 {
+            note_events.write(KeyInputEvent(make_key_event(
+                register,
+                Event::NoteOn { note, vel },
+            )));
+        }
+    }
+}
@@ -1,22 +1,17 @@
-use crate::KeyboardRegister;
+use bevy::asset::{Asset, AssetLoader, LoadContext, io::Reader};
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
 
-pub fn note_is_black(note: u8) -> bool {
-    match note % 12 {
-        1 | 3 | 6 | 8 | 10 => true,
-        _ => false
-    }
+pub fn note_name(note: u8) -> String {
+    format!("{}{}", pitch_class_name(note), note as i32 / 12 - 1)
 }
 
-pub fn on_lower(lower: bool, note: Option<u8>) -> Option<(KeyboardRegister, u8)> {
-    note.map(|n| {
-        (
-            match lower {
-                true => KeyboardRegister::Lower,
-                false => KeyboardRegister::Upper,
-            },
-            n,
-        )
-    })
+/// Name of a note without its octave
+pub fn pitch_class_name(note: u8) -> &'static str {
+    const NAMES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    NAMES[note as usize % 12]
 }
 
 pub fn make_note(from_note: u8, white_key_advance: usize, black_key: bool) -> Option<u8> {
@@ -45,3 +40,40 @@ fn black_key_above(from_note: u8) -> Option<u8> {
         _ => None,
     }
 }
+
+/// Loads any asset type that deserializes directly from JSON
+pub struct JsonAssetLoader<A> {
+    extensions: &'static [&'static str],
+    _asset: PhantomData<fn() -> A>,
+}
+
+impl<A> JsonAssetLoader<A> {
+    pub fn new(extensions: &'static [&'static str]) -> Self {
+        Self {
+            extensions,
+            _asset: PhantomData,
+        }
+    }
+}
+
+impl<A: Asset + DeserializeOwned> AssetLoader for JsonAssetLoader<A> {
+    type Asset = A;
+    type Settings = ();
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).await?;
+        let asset = serde_json::from_slice::<A>(&bytes)?;
+        Ok(asset)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        self.extensions
+    }
+}
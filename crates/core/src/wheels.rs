@@ -0,0 +1,197 @@
+use crate::{KeyEvent, KeyboardRegister, Settings, input::make_key_event};
+use bevy::{
+    input::mouse::{MouseScrollUnit, MouseWheel},
+    prelude::*,
+};
+use bevy_midi_graph::midi::event::Event;
+use std::f32::consts::FRAC_PI_2;
+
+const BEND_UP_KEY: KeyCode = KeyCode::ArrowUp;
+const BEND_DOWN_KEY: KeyCode = KeyCode::ArrowDown;
+const MODULATION_UP_KEY: KeyCode = KeyCode::ArrowRight;
+const MODULATION_DOWN_KEY: KeyCode = KeyCode::ArrowLeft;
+pub(crate) const BEND_RANGE_MAX: u8 = 24;
+/// How far the wheels move in a second while keys are held
+const BEND_SPEED: f32 = 4.0;
+const SPRING_SPEED: f32 = 6.0;
+const MODULATION_SPEED: f32 = 1.0;
+const SCROLL_LINE_BEND: f32 = 0.25;
+const SCROLL_PIXEL_BEND: f32 = 0.01;
+/// The bend springs back once the mouse wheel has been still this long
+const SCROLL_HOLD_SECS: f32 = 0.15;
+/// Smaller changes of pitch are not worth sending
+const PITCH_TOLERANCE: f32 = 0.0005;
+const WHEEL_RADIUS: f32 = 0.12;
+const WHEEL_WIDTH: f32 = 0.05;
+const WHEEL_TRAVEL: f32 = 0.8;
+
+pub struct WheelsPlugin;
+
+impl Plugin for WheelsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Wheels>()
+            .add_systems(Startup, create_wheels)
+            .add_systems(Update, (control_wheels, send_pitch, turn_wheels).chain());
+    }
+}
+
+/// Pitch bend and modulation wheels.
+/// The up and down arrows bend the pitch while held, as does the mouse
+/// wheel, springing back when let go. The bend reaches the settings'
+/// `bend_range` in semitones at either end, and Ctrl with the up and down
+/// arrows changes the range. The left and right arrows lower and raise the
+/// modulation, which stays where it is left and adds vibrato on the output
+/// bus.
+#[derive(Resource, Default)]
+pub struct Wheels {
+    pub bend: f32,
+    pub modulation: f32,
+}
+
+#[derive(Component, Clone, Copy)]
+enum WheelDisplay {
+    Bend,
+    Modulation,
+}
+
+fn create_wheels(
+    mut commands: Commands,
+    mut material_assets: ResMut<Assets<StandardMaterial>>,
+    mut mesh_assets: ResMut<Assets<Mesh>>,
+) {
+    let wheel_material = material_assets.add(StandardMaterial {
+        base_color: Color::srgb(0.15, 0.15, 0.15).into(),
+        ..default()
+    });
+    let marker_material = material_assets.add(StandardMaterial {
+        base_color: Color::srgb(0.9, 0.9, 0.9).into(),
+        ..default()
+    });
+    let wheel_mesh = mesh_assets.add(Cylinder::new(WHEEL_RADIUS, WHEEL_WIDTH));
+    let marker_mesh = mesh_assets.add(Cuboid::new(0.02, WHEEL_WIDTH * 1.1, 0.04));
+    for (display, x) in [
+        (WheelDisplay::Modulation, -1.9),
+        (WheelDisplay::Bend, -1.65),
+    ] {
+        commands.spawn((
+            display,
+            Mesh3d(wheel_mesh.clone()),
+            MeshMaterial3d(wheel_material.clone()),
+            Transform::from_xyz(x, 0.0, -1.0).with_rotation(wheel_rotation(0.0)),
+            children![(
+                Mesh3d(marker_mesh.clone()),
+                MeshMaterial3d(marker_material.clone()),
+                Transform::from_xyz(WHEEL_RADIUS, 0.0, 0.0),
+            )],
+        ));
+    }
+}
+
+/// Wheels turn about the x axis, their marker rolling away from the player
+/// as they are pushed up
+fn wheel_rotation(position: f32) -> Quat {
+    Quat::from_rotation_x(-position * WHEEL_TRAVEL) * Quat::from_rotation_z(FRAC_PI_2)
+}
+
+fn approach(value: f32, target: f32, step: f32) -> f32 {
+    match value < target {
+        true => (value + step).min(target),
+        false => (value - step).max(target),
+    }
+}
+
+fn control_wheels(
+    inputs: Res<ButtonInput<KeyCode>>,
+    mut scroll_events: EventReader<MouseWheel>,
+    time: Res<Time>,
+    mut settings: ResMut<Settings>,
+    mut wheels: ResMut<Wheels>,
+    mut scrolled_secs: Local<f32>,
+) {
+    let now_secs = time.elapsed_secs();
+    let delta_secs = time.delta_secs();
+    let scrolled: f32 = scroll_events
+        .read()
+        .map(|event| match event.unit {
+            MouseScrollUnit::Line => event.y * SCROLL_LINE_BEND,
+            MouseScrollUnit::Pixel => event.y * SCROLL_PIXEL_BEND,
+        })
+        .sum();
+    // With Alt held the arrows change the effects instead
+    if inputs.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]) {
+        return;
+    }
+    let ctrl = inputs.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    if ctrl && (inputs.just_pressed(BEND_UP_KEY) || inputs.just_pressed(BEND_DOWN_KEY)) {
+        settings.bend_range = match inputs.just_pressed(BEND_UP_KEY) {
+            true => (settings.bend_range + 1).min(BEND_RANGE_MAX),
+            false => settings.bend_range.saturating_sub(1).max(1),
+        };
+    }
+
+    let held = match (
+        !ctrl && inputs.pressed(BEND_UP_KEY),
+        !ctrl && inputs.pressed(BEND_DOWN_KEY),
+    ) {
+        (true, false) => Some(1.0),
+        (false, true) => Some(-1.0),
+        _ => None,
+    };
+    let bend = if scrolled != 0.0 {
+        *scrolled_secs = now_secs;
+        (wheels.bend + scrolled).clamp(-1.0, 1.0)
+    } else if let Some(target) = held {
+        approach(wheels.bend, target, BEND_SPEED * delta_secs)
+    } else if now_secs - *scrolled_secs > SCROLL_HOLD_SECS {
+        approach(wheels.bend, 0.0, SPRING_SPEED * delta_secs)
+    } else {
+        wheels.bend
+    };
+    if bend != wheels.bend {
+        wheels.bend = bend;
+    }
+
+    let modulation_step = MODULATION_SPEED * delta_secs;
+    let modulation = match (
+        inputs.pressed(MODULATION_UP_KEY),
+        inputs.pressed(MODULATION_DOWN_KEY),
+    ) {
+        (true, false) => (wheels.modulation + modulation_step).min(1.0),
+        (false, true) => (wheels.modulation - modulation_step).max(0.0),
+        _ => wheels.modulation,
+    };
+    if modulation != wheels.modulation {
+        wheels.modulation = modulation;
+    }
+}
+
+/// Send the pitch bend to both registers whenever it moves
+fn send_pitch(
+    wheels: Res<Wheels>,
+    settings: Res<Settings>,
+    mut key_events: EventWriter<KeyEvent>,
+    mut sent_multiplier: Local<Option<f32>>,
+) {
+    let semitones = wheels.bend * settings.bend_range as f32;
+    let multiplier = 2.0_f32.powf(semitones / 12.0);
+    if (multiplier - sent_multiplier.unwrap_or(1.0)).abs() < PITCH_TOLERANCE {
+        return;
+    }
+    *sent_multiplier = Some(multiplier);
+    for register in [KeyboardRegister::Lower, KeyboardRegister::Upper] {
+        key_events.write(make_key_event(register, Event::PitchMultiplier(multiplier)));
+    }
+}
+
+fn turn_wheels(wheels: Res<Wheels>, mut wheel_query: Query<(&WheelDisplay, &mut Transform)>) {
+    if !wheels.is_changed() {
+        return;
+    }
+    for (display, mut transform) in wheel_query.iter_mut() {
+        let position = match display {
+            WheelDisplay::Bend => wheels.bend,
+            WheelDisplay::Modulation => wheels.modulation * 2.0 - 1.0,
+        };
+        transform.rotation = wheel_rotation(position);
+    }
+}
@@ -1,14 +1,89 @@
 use bevy::prelude::*;
-use shining_piano_core::ShiningPianoPlugin;
+use shining_piano_core::{
+    HeadlessRenderPlugin, Mixer, OfflineRenderEvent, RecorderSettings, ShiningPianoPlugin,
+    WavSampleFormat,
+};
+use std::path::Path;
 
-fn main() {
+const ASSETS_DIR: &str = "../../assets";
+const USAGE: &str =
+    "Usage: shining-piano render <input.mid> <output.wav> [program] [int16|int24|float]";
+
+fn main() -> AppExit {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("render") => render(&args[1..]),
+        _ => play(),
+    }
+}
+
+fn play() -> AppExit {
     App::new()
         .add_plugins((
             DefaultPlugins.set(AssetPlugin {
-                file_path: "../../assets".to_owned(),
+                file_path: ASSETS_DIR.to_owned(),
                 ..default()
             }),
             ShiningPianoPlugin,
         ))
-        .run();
+        .insert_resource(RecorderSettings {
+            save_dir: format!("{}/recordings", ASSETS_DIR),
+        })
+        .run()
+}
+
+/// Render a MIDI file to WAV without opening a window
+fn render(args: &[String]) -> AppExit {
+    let job = match render_job(args) {
+        Ok(job) => job,
+        Err(error) => {
+            eprintln!("{}\n{}", error, USAGE);
+            return AppExit::error();
+        }
+    };
+    App::new()
+        .add_plugins((
+            MinimalPlugins,
+            AssetPlugin {
+                file_path: ASSETS_DIR.to_owned(),
+                ..default()
+            },
+            HeadlessRenderPlugin { job },
+        ))
+        .run()
+}
+
+fn render_job(args: &[String]) -> Result<OfflineRenderEvent, String> {
+    let [input, output, options @ ..] = args else {
+        return Err("Missing input or output file".to_owned());
+    };
+    let midi = std::fs::read(input).map_err(|error| format!("Cannot read {}: {}", input, error))?;
+    let program_no = match options.first() {
+        Some(program) => program
+            .parse()
+            .map_err(|_| format!("Invalid program number {}", program))?,
+        None => 1,
+    };
+    let sample_format = match options.get(1).map(String::as_str) {
+        None | Some("int16") => WavSampleFormat::Int16,
+        Some("int24") => WavSampleFormat::Int24,
+        Some("float") => WavSampleFormat::Float,
+        Some(format) => return Err(format!("Invalid sample format {}", format)),
+    };
+    let output = Path::new(output);
+    let save_dir = output
+        .parent()
+        .map_or(String::new(), |dir| dir.display().to_string());
+    let file_name = output
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| "Invalid output file".to_owned())?;
+    Ok(OfflineRenderEvent {
+        midi,
+        program_no,
+        mixer: Mixer::default(),
+        save_dir,
+        file_name,
+        sample_format,
+    })
 }